@@ -29,5 +29,13 @@ while (true) {
 
 @module
 */
-export { Pty } from "./src/mod.ts";
+export {
+  LogLevel,
+  OpenPty,
+  Pty,
+  PtyReader,
+  setLogCallback,
+  validateCommand,
+} from "./src/mod.ts";
 export type { Command, PtySize } from "./src/ffi.ts";
+export { ErrorKind, PtyError } from "./src/error.ts";
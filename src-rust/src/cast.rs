@@ -0,0 +1,62 @@
+use crate::{
+    log::{log, LogLevel},
+    utils::decode_utf8,
+    Decode, Result,
+};
+use portable_pty::PtySize;
+use std::{
+    fs::File,
+    io::Write,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Writes the output to a file in the asciinema v2 format
+///
+/// https://docs.asciinema.org/manual/asciicast/v2/
+pub struct Cast {
+    file: File,
+    started: Instant,
+    // bytes of a utf8 sequence that was split between reads
+    pending: Vec<u8>,
+}
+
+impl Cast {
+    /// Creates the file and writes the header
+    pub fn create(path: &str, size: PtySize, started: Instant) -> Result<Self> {
+        let mut file = File::create(path)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = serde_json::json!({
+            "version": 2,
+            "width": size.cols,
+            "height": size.rows,
+            "timestamp": timestamp,
+        });
+        writeln!(file, "{header}")?;
+        Ok(Self {
+            file,
+            started,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Writes an output event, returns false if writing failed and the recording should stop
+    pub fn write_output(&mut self, bytes: &[u8]) -> bool {
+        self.pending.extend_from_slice(bytes);
+        // the format requires utf8, so invalid sequences are replaced
+        let data = decode_utf8(&mut self.pending, Decode::Lossy).unwrap_or_default();
+        if data.is_empty() {
+            return true;
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        let event = serde_json::json!([time, "o", data]);
+        // one write per event, so a crash doesn't leave a partial line
+        if let Err(err) = self.file.write_all(format!("{event}\n").as_bytes()) {
+            log(
+                LogLevel::Error,
+                &format!("failed to write the recording, it's stopped: {err}"),
+            );
+            return false;
+        }
+        true
+    }
+}
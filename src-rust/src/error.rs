@@ -0,0 +1,94 @@
+use serde::Serialize;
+use std::{
+    ffi::{IntoStringError, NulError},
+    fmt,
+    str::Utf8Error,
+};
+
+/// The kind of an error
+///
+/// The numeric values are part of the ffi api, don't change them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+// some kinds are only constructed on some platforms
+#[allow(dead_code)]
+pub enum ErrorKind {
+    Other = 0,
+    /// A value sent by the caller isn't valid JSON or doesn't have the expected shape
+    InvalidJson = 1,
+    /// A string isn't valid utf8 or contains a null byte
+    InvalidString = 2,
+    NotFound = 3,
+    PermissionDenied = 4,
+    /// Any other io error
+    Io = 5,
+    /// A background thread of the pty exited
+    Disconnected = 6,
+    /// The operation isn't supported on this platform
+    Unsupported = 7,
+    /// An argument passed by the caller is invalid
+    InvalidInput = 8,
+    /// The process was killed because it ran longer than the timeout or didn't output anything for too long
+    TimedOut = 9,
+    /// A blocking read was interrupted by pty_interrupt_read
+    Interrupted = 10,
+}
+
+/// An error with a kind that can be matched on from the other side of the ffi
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PtyError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl PtyError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for PtyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PtyError {}
+
+/// The error as it's sent through the ffi
+#[derive(Serialize)]
+pub struct SerializedError {
+    pub kind: u8,
+    pub message: String,
+}
+
+impl From<Box<dyn std::error::Error>> for SerializedError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        Self {
+            kind: error_kind(&*err) as u8,
+            message: err.to_string(),
+        }
+    }
+}
+
+fn error_kind(err: &(dyn std::error::Error + 'static)) -> ErrorKind {
+    if let Some(err) = err.downcast_ref::<PtyError>() {
+        return err.kind;
+    }
+    if err.is::<serde_json::Error>() {
+        return ErrorKind::InvalidJson;
+    }
+    if err.is::<Utf8Error>() || err.is::<NulError>() || err.is::<IntoStringError>() {
+        return ErrorKind::InvalidString;
+    }
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return match err.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Io,
+        };
+    }
+    ErrorKind::Other
+}
@@ -0,0 +1,755 @@
+use crate::{
+    error::{ErrorKind, PtyError},
+    log::{self, log, LogLevel},
+    utils::{boxed_error_to_cstring, cstr_to_type, decode_length_prefixed, type_to_cstr},
+    CPtySize, Command, ExitCallback, Pty, ResizeSize, Result,
+};
+use portable_pty::{PtyPair, PtySize};
+use std::{
+    ffi::CString, mem::ManuallyDrop, os::raw::c_char, sync::atomic::Ordering, time::Duration,
+};
+
+mod read;
+mod write;
+pub use read::*;
+pub use write::*;
+
+// note: need to be careful with names with no_mangle extern C
+// for example extern C write, will cause weird bugs
+
+/// # Safety
+/// - Requires a valid pointer to a Command
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the pid to, 0 if the pid couldn't be determined
+///
+/// Returns -1 on error
+#[no_mangle]
+// can't use new since its a reserved keyword in javascript
+pub unsafe extern "C" fn pty_create(command: *mut c_char, result: *mut usize, pid: *mut u32) -> i8 {
+    let pty = (|| -> Result<Box<Pty>> {
+        let command = cstr_to_type::<Command>(command)?;
+        let pty = Pty::create(command)?;
+        Ok(Box::new(pty))
+    })();
+    match pty {
+        Ok(pty) => {
+            *pid = pty.pid.unwrap_or(0);
+            *result = Box::into_raw(pty) as usize;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Command
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Checks the command without opening a pty or starting a process:
+/// the options, that cwd is a directory, the environment variable names and that the program can be found
+/// The process can still fail to start, for example if the program is removed in between
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_validate_command(command: *mut c_char, result: *mut usize) -> i8 {
+    match (|| -> Result<()> { cstr_to_type::<Command>(command)?.check() })() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a utf8 buffer of size cmd_len
+/// - Requires a valid pointer to a buffer of size args_len, or null if args_len is 0
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the pid to, 0 if the pid couldn't be determined
+///
+/// Same as pty_create without decoding the command from JSON, the other Command options use their defaults
+/// The args are utf8 strings, each one prefixed by its length in bytes as a little endian u32
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_create_simple(
+    cmd: *const u8,
+    cmd_len: usize,
+    args: *const u8,
+    args_len: usize,
+    result: *mut usize,
+    pid: *mut u32,
+) -> i8 {
+    let pty = (|| -> Result<Box<Pty>> {
+        let cmd = std::str::from_utf8(std::slice::from_raw_parts(cmd, cmd_len))?.to_owned();
+        let args = if args_len == 0 {
+            Vec::new()
+        } else {
+            decode_length_prefixed(std::slice::from_raw_parts(args, args_len))
+                .map_err(|err| PtyError::new(ErrorKind::InvalidInput, err.to_string()))?
+        };
+        let pty = Pty::create(Command {
+            cmd,
+            args,
+            ..Default::default()
+        })?;
+        Ok(Box::new(pty))
+    })();
+    match pty {
+        Ok(pty) => {
+            *pid = pty.pid.unwrap_or(0);
+            *result = Box::into_raw(pty) as usize;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a PtySize encoded as a JSON Cstring, or null for the default size
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Opens a pty without starting a process, use pty_spawn to start it
+/// The result is a handle that must be passed to pty_spawn or pty_open_close
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_open(size: *mut c_char, result: *mut usize) -> i8 {
+    let pair = (|| -> Result<Box<PtyPair>> {
+        let size = if size.is_null() {
+            PtySize::default()
+        } else {
+            cstr_to_type::<PtySize>(size)?
+        };
+        Ok(Box::new(Pty::open(size)?))
+    })();
+    match pair {
+        Ok(pair) => {
+            *result = Box::into_raw(pair) as usize;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a pty opened with pty_open, it's consumed even on error
+/// - Requires a valid pointer to a Command
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the pid to, 0 if the pid couldn't be determined
+///
+/// Same as pty_create but the process is started in the opened pty
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_spawn(
+    pair: *mut PtyPair,
+    command: *mut c_char,
+    result: *mut usize,
+    pid: *mut u32,
+) -> i8 {
+    let pair = Box::from_raw(pair);
+    let pty = (|| -> Result<Box<Pty>> {
+        let command = cstr_to_type::<Command>(command)?;
+        let pty = Pty::spawn(*pair, command)?;
+        Ok(Box::new(pty))
+    })();
+    match pty {
+        Ok(pty) => {
+            *pid = pty.pid.unwrap_or(0);
+            *result = Box::into_raw(pty) as usize;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a pty opened with pty_open
+///
+/// Closes a pty that wasn't passed to pty_spawn
+#[no_mangle]
+pub unsafe extern "C" fn pty_open_close(pair: *mut PtyPair) {
+    // NOTE: Dropping the pty doensn't work on windows and trigger random bugs https://github.com/sigmaSd/deno-pty-ffi/issues/3
+    if cfg!(windows) {
+        let _pair = ManuallyDrop::new(Box::from_raw(pair));
+    } else {
+        drop(Box::from_raw(pair));
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_get_size(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match (|| -> Result<CString> {
+        let size = this.get_size()?;
+        type_to_cstr(&size)
+    })() {
+        Ok(size) => {
+            *result = size.into_raw() as _;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires 4 valid pointers to buffers of size 2
+///   to write the rows, cols, pixel width and pixel height to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_get_size without encoding the size as JSON
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_get_size_raw(
+    this: *mut Pty,
+    rows: *mut u16,
+    cols: *mut u16,
+    pixel_width: *mut u16,
+    pixel_height: *mut u16,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.get_size() {
+        Ok(size) => {
+            *rows = size.rows;
+            *cols = size.cols;
+            *pixel_width = size.pixel_width;
+            *pixel_height = size.pixel_height;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a CPtySize
+///   to write the size to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_get_size with the size as a C struct
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_get_size_c(
+    this: *mut Pty,
+    size: *mut CPtySize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.get_size() {
+        Ok(current) => {
+            *size = current.into();
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_resize with the size as a C struct passed by value, all the fields are applied
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_resize_c(this: *mut Pty, size: CPtySize, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.resize(size.into()) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_set_raw(this: *mut Pty, raw: bool, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.set_raw(raw) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_ttyname(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match (|| -> Result<CString> { Ok(CString::new(this.tty_name()?)?) })() {
+        Ok(name) => {
+            *result = name.into_raw() as _;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a PtySize encoded as CString
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// The pixel size is optional, it defaults to the current one
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_resize(this: *mut Pty, size: *mut c_char, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match (|| -> Result<()> {
+        let size = cstr_to_type::<ResizeSize>(size)?;
+        // programs that use the pixel size would be confused by 0
+        let current = this.get_size()?;
+        this.resize(PtySize {
+            rows: size.rows,
+            cols: size.cols,
+            pixel_width: size.pixel_width.unwrap_or(current.pixel_width),
+            pixel_height: size.pixel_height.unwrap_or(current.pixel_height),
+        })?;
+        Ok(())
+    })() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_resize_raw but reads the size back to check it was applied, and sends SIGWINCH again on unix
+///
+/// Returns -1 on error, including when the size wasn't applied
+#[no_mangle]
+pub unsafe extern "C" fn pty_resize_verified(
+    this: *mut Pty,
+    rows: u16,
+    cols: u16,
+    pixel_width: u16,
+    pixel_height: u16,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.resize_verified(PtySize {
+        rows,
+        cols,
+        pixel_width,
+        pixel_height,
+    }) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_resize without decoding the size from JSON
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_resize_raw(
+    this: *mut Pty,
+    rows: u16,
+    cols: u16,
+    pixel_width: u16,
+    pixel_height: u16,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.resize(PtySize {
+        rows,
+        cols,
+        pixel_width,
+        pixel_height,
+    }) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_signal(this: *mut Pty, signal: i32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.signal(signal) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_signal but the signal is sent to the process group of the spawned process
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_signal_group(this: *mut Pty, signal: i32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.signal_group(signal) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Sends SIGTERM to the process, and kills it if it didn't exit after grace_ms
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_terminate(this: *mut Pty, grace_ms: u64, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.terminate(Duration::from_millis(grace_ms)) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Blocks until the process exits or the timeout elapses, a negative timeout waits forever
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+#[no_mangle]
+pub unsafe extern "C" fn pty_wait(this: *mut Pty, timeout_ms: i64, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    match this.wait(timeout) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 1
+///   to write the result to, 1 if the process is running, 0 otherwise
+#[no_mangle]
+pub unsafe extern "C" fn pty_is_alive(this: *mut Pty, alive: *mut u8) -> i8 {
+    let this = unsafe { &*this };
+    *alive = !this.exit.exited() as u8;
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 1
+///   to write the result to, 1 if the output has ended, 0 otherwise
+///
+/// The output ends once the process exited and all of it was queued, the readers may still have
+/// data to read. Unlike reading End, this doesn't finish any reader
+#[no_mangle]
+pub unsafe extern "C" fn pty_has_ended(this: *mut Pty, ended: *mut u8) -> i8 {
+    let this = unsafe { &*this };
+    *ended = this.exit.end_sent.load(Ordering::SeqCst) as u8;
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the fd to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Returns a new fd to the slave of the pty, unix only
+/// The fd is owned by the caller, the output doesn't end while it's open since the slave stays open
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_slave_fd(this: *mut Pty, fd: *mut i32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.slave_fd() {
+        Ok(slave_fd) => {
+            #[cfg(unix)]
+            {
+                *fd = std::os::fd::IntoRawFd::into_raw_fd(slave_fd);
+            }
+            #[cfg(windows)]
+            {
+                *fd = slave_fd;
+            }
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the pid to
+///
+/// Returns 1 if the pid couldn't be determined
+#[no_mangle]
+pub unsafe extern "C" fn pty_pid(this: *mut Pty, pid: *mut u32) -> i8 {
+    let this = unsafe { &*this };
+    match this.pid {
+        Some(child_pid) => {
+            *pid = child_pid;
+            0
+        }
+        None => 1,
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the exit code to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Returns -1 on error
+/// Returns 1 if the process is still running
+#[no_mangle]
+pub unsafe extern "C" fn pty_exit_code(this: *mut Pty, code: *mut i32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.exit_code() {
+        Ok(Some(exit_code)) => {
+            *code = exit_code as i32;
+            0
+        }
+        Ok(None) => 1,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the signal number to
+///
+/// Returns 1 if the process is still running or wasn't terminated by a signal
+#[no_mangle]
+pub unsafe extern "C" fn pty_exit_signal(this: *mut Pty, signal: *mut i32) -> i8 {
+    let this = unsafe { &*this };
+    match this.exit_signal() {
+        Some(exit_signal) => {
+            *signal = exit_signal;
+            0
+        }
+        None => 1,
+    }
+}
+
+/// Registers a callback that receives the errors and warnings of the background threads
+/// Pass null to remove it
+///
+/// The callback can be called from any thread
+#[no_mangle]
+pub extern "C" fn pty_set_log_callback(callback: Option<log::LogCallback>) {
+    log::set_callback(callback);
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+///
+/// Registers a callback that is called from the waiter thread once the process exits
+/// It's called right away if the process already exited, pass null to remove it
+///
+/// The callback can still be called after the pty is closed, if the process is left running
+#[no_mangle]
+pub unsafe extern "C" fn pty_set_exit_callback(this: *mut Pty, callback: Option<ExitCallback>) {
+    let this = unsafe { &*this };
+    this.exit.set_callback(callback);
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+///
+/// Makes the calls in flight on other threads return, so the pty can be closed once they do:
+/// the blocked reads are interrupted, and with kill the process is killed like pty_close does,
+/// so wait and the reads until the end return too
+///
+/// The pty is still valid, it has to be closed with pty_close_nokill after the calls returned
+#[no_mangle]
+pub unsafe extern "C" fn pty_cancel(this: *mut Pty, kill: u8) {
+    // only a shared reference, the calls in flight still use the pty
+    let this = unsafe { &*this };
+    if kill != 0 {
+        if let Err(err) = this.kill_on_close() {
+            log(
+                LogLevel::Warn,
+                &format!("failed to kill the process: {err}"),
+            );
+        }
+    }
+    this.interrupt_read();
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - No other call with this pty can be in flight, the pty is freed, use pty_cancel to make them return
+#[no_mangle]
+pub unsafe extern "C" fn pty_close(this: *mut Pty) {
+    // NOTE: Dropping the pty doensn't work on windows and trigger random bugs https://github.com/sigmaSd/deno-pty-ffi/issues/3
+    // kill the whole tree, so the children don't keep the pty open
+    if cfg!(windows) {
+        let this = ManuallyDrop::new(Box::from_raw(this));
+        if let Err(err) = this.kill_on_close() {
+            log(
+                LogLevel::Warn,
+                &format!("failed to kill the process: {err}"),
+            );
+        }
+    } else {
+        let this = Box::from_raw(this);
+        // NOTE: maybe propage the possible error
+        if let Err(err) = this.kill_on_close() {
+            log(
+                LogLevel::Warn,
+                &format!("failed to kill the process: {err}"),
+            );
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - No other call with this pty can be in flight, the pty is freed, use pty_cancel to make them return
+///
+/// Same as pty_close but the process is left running
+#[no_mangle]
+pub unsafe extern "C" fn pty_close_nokill(this: *mut Pty) {
+    // NOTE: Dropping the pty doensn't work on windows and trigger random bugs https://github.com/sigmaSd/deno-pty-ffi/issues/3
+    if cfg!(windows) {
+        let _this = ManuallyDrop::new(Box::from_raw(this));
+    } else {
+        drop(Box::from_raw(this));
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - No other call with this pty can be in flight, the pty is freed, use pty_cancel to make them return
+///
+/// Same as pty_close, or pty_close_nokill if kill is 0, but the queued writes are written to the process first
+/// so a write followed by a close doesn't lose the input. Blocks up to timeout_ms if the process doesn't read it
+#[no_mangle]
+pub unsafe extern "C" fn pty_close_flush(this: *mut Pty, timeout_ms: u64, kill: u8) {
+    match (*this).flush_timeout(Some(Duration::from_millis(timeout_ms))) {
+        Ok(true) => {}
+        Ok(false) => log(
+            LogLevel::Warn,
+            "timed out writing the queued data before closing",
+        ),
+        Err(err) => log(
+            LogLevel::Warn,
+            &format!("failed to flush before closing: {err}"),
+        ),
+    }
+    if kill != 0 {
+        pty_close(this);
+    } else {
+        pty_close_nokill(this);
+    }
+}
@@ -0,0 +1,563 @@
+use crate::{utils::boxed_error_to_cstring, Message, Pty, PtyReader, ReadUntil, Result};
+use std::{ffi::CString, sync::atomic::Ordering, time::Duration};
+
+/// What the read functions return to the caller
+enum ReadResult {
+    Data(CString),
+    // the process exited after this data, the next read returns End
+    LastData(CString),
+    // no data yet, or the timeout elapsed
+    Empty,
+    End,
+}
+
+impl ReadResult {
+    /// `last` is whether the process exited after the data
+    fn new(msg: Message, last: bool) -> Result<Self> {
+        match msg {
+            Message::Data(data) => {
+                let data = CString::new(data.replace('\0', ""))?;
+                if last {
+                    Ok(Self::LastData(data))
+                } else {
+                    Ok(Self::Data(data))
+                }
+            }
+            Message::End => Ok(Self::End),
+            Message::Error(err) => Err(err.into()),
+        }
+    }
+
+    /// Same as new for the reads that don't wait, they return empty data when there is nothing yet
+    fn new_nonblocking(msg: Message, last: bool) -> Result<Self> {
+        match msg {
+            Message::Data(data) if data.is_empty() => Ok(Self::Empty),
+            msg => Self::new(msg, last),
+        }
+    }
+
+    /// Writes the data or the error to result and returns the code of the read functions
+    ///
+    /// # Safety
+    /// - Requires a valid pointer to a buffer of size 8
+    unsafe fn write(read: Result<Self>, result: *mut usize) -> i8 {
+        match read {
+            Ok(Self::Data(data)) => {
+                *result = data.into_raw() as _;
+                0
+            }
+            Ok(Self::LastData(data)) => {
+                *result = data.into_raw() as _;
+                98
+            }
+            Ok(Self::Empty) => 1,
+            Ok(Self::End) => 99,
+            Err(err) => {
+                *result = boxed_error_to_cstring(err).into_raw() as _;
+                -1
+            }
+        }
+    }
+}
+
+/// Writes the data read so far or the error to result and returns the code of the read until functions
+///
+/// # Safety
+/// - Requires a valid pointer to a buffer of size 8
+unsafe fn write_read_until(read: Result<(String, ReadUntil)>, result: *mut usize) -> i8 {
+    match read.and_then(|(data, status)| Ok((CString::new(data.replace('\0', ""))?, status))) {
+        Ok((data, status)) => {
+            *result = data.into_raw() as _;
+            match status {
+                ReadUntil::Found => 0,
+                ReadUntil::Timeout => 1,
+                ReadUntil::End => 99,
+            }
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the reader to
+///
+/// Creates another reader of the pty output, it can be used from another thread
+/// The readers share the output, each chunk is returned by only one of them
+/// The process exit is reported by the reader that receives it, the others report it when the pty is closed
+#[no_mangle]
+pub unsafe extern "C" fn pty_clone_reader(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    *result = Box::into_raw(Box::new(this.clone_reader())) as usize;
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a reader created with pty_clone_reader
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Same as pty_read for a cloned reader
+///
+/// Returns -1 on error
+/// Returns 1 if there is no data yet, the result isn't written
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_reader_read(reader: *mut PtyReader, result: *mut usize) -> i8 {
+    let reader = unsafe { &*reader };
+    let read = (|| ReadResult::new_nonblocking(reader.read()?, false))();
+    ReadResult::write(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a reader created with pty_clone_reader
+/// - No read with this reader can be in flight
+///
+/// Frees only this reader, the pty and the other readers keep working
+/// The reader doesn't borrow the pty, so it can be freed before or after pty_close
+/// The output peeked by this reader is lost
+#[no_mangle]
+pub unsafe extern "C" fn pty_reader_free(reader: *mut PtyReader) {
+    drop(Box::from_raw(reader));
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+/// - Requires a valid pointer to a buffer of size 1
+///   to write whether the process exited to
+///
+/// Returns all the buffered data without waiting, the data can be empty
+/// `ended` is set to 1 if the process exited after this data, the next reads return 99
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_drain(this: *mut Pty, result: *mut usize, ended: *mut u8) -> i8 {
+    let this = unsafe { &*this };
+    match (|| -> Result<(CString, bool)> {
+        let (data, ended) = this.drain()?;
+        Ok((CString::new(data.replace('\0', ""))?, ended))
+    })() {
+        Ok((data, data_ended)) => {
+            *result = data.into_raw() as _;
+            *ended = data_ended as u8;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Returns the buffered data without consuming it, the next pty_read returns it
+///
+/// Returns -1 on error
+/// Returns 99 if the process exited after this data
+#[no_mangle]
+pub unsafe extern "C" fn pty_peek(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    let (data, ended) = this.reader.peek();
+    match CString::new(data.replace('\0', "")) {
+        Ok(data) => {
+            *result = data.into_raw() as _;
+            if ended {
+                99
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err.into()).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Returns -1 on error
+/// Returns 1 if there is no data yet, the result isn't written
+/// Returns 98 with the last data when the process exited, the next read returns 99
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    // TODO: add a test for null byte inside str from read
+    let read = (|| ReadResult::new_nonblocking(this.read()?, this.reader.ended()))();
+    ReadResult::write(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Same as pty_read but reads the stderr, requires separate_stderr
+///
+/// Returns -1 on error
+/// Returns 1 if there is no data yet, the result isn't written
+/// Returns 99 when the stderr is closed
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_stderr(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| ReadResult::new_nonblocking(this.read_stderr()?, false))();
+    ReadResult::write(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the timestamp to
+///
+/// Same as pty_read, also writes when the data was received
+/// in nanoseconds since the pty was created
+///
+/// Returns -1 on error
+/// Returns 1 if there is no data yet, the result and the timestamp aren't written
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_timed(this: *mut Pty, result: *mut usize, ts: *mut u64) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| {
+        let (msg, at) = this.read_timed()?;
+        let read = ReadResult::new_nonblocking(msg, false)?;
+        if let ReadResult::Data(_) = read {
+            *ts = at.as_nanos() as u64;
+        }
+        Ok(read)
+    })();
+    ReadResult::write(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+///
+/// Makes a read blocked in another thread, like pty_read_timeout or pty_read_until, return -1
+/// with an Interrupted error. If no read is blocked the next blocking read is interrupted
+///
+/// Only one read is interrupted per call, the non-blocking reads are not affected
+#[no_mangle]
+pub unsafe extern "C" fn pty_interrupt_read(this: *mut Pty) {
+    let this = unsafe { &*this };
+    this.interrupt_read();
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Blocks until data is available, the process exits or the timeout elapses
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+/// Returns 98 with the last data when the process exited, the next read returns 99
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_timeout(
+    this: *mut Pty,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| match this.read_timeout(Duration::from_millis(timeout_ms))? {
+        Some(msg) => ReadResult::new(msg, this.reader.ended()),
+        None => Ok(ReadResult::Empty),
+    })();
+    ReadResult::write(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size buf_cap
+///   to write the data to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the number of bytes written to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_read but writes the data into the caller's buffer, the data that doesn't fit is
+/// returned by the next call. The data can end in the middle of a utf8 sequence.
+/// Don't mix it with the other read functions, they don't return the data left by this one
+///
+/// Returns -1 on error
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_into(
+    this: *mut Pty,
+    buf: *mut u8,
+    buf_cap: usize,
+    written: *mut usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let buf = std::slice::from_raw_parts_mut(buf, buf_cap);
+    match this.read_into(buf) {
+        Ok(Some(n)) => {
+            *written = n;
+            0
+        }
+        Ok(None) => 99,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a utf8 buffer of size needle_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Reads until the needle appears in the output, the process exits or the timeout elapses
+/// The result is everything read so far
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_until(
+    this: *mut Pty,
+    needle: *const u8,
+    needle_len: usize,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| {
+        let needle = std::str::from_utf8(std::slice::from_raw_parts(needle, needle_len))?;
+        this.read_until(needle, Duration::from_millis(timeout_ms))
+    })();
+    write_read_until(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a utf8 buffer of size pattern_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Same as pty_read_until but waits for a match of the regex pattern, for prompts that change
+/// The syntax is the one of the regex crate, the last pattern is kept compiled
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_read_until_regex(
+    this: *mut Pty,
+    pattern: *const u8,
+    pattern_len: usize,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| {
+        let pattern = std::str::from_utf8(std::slice::from_raw_parts(pattern, pattern_len))?;
+        this.read_until_regex(pattern, Duration::from_millis(timeout_ms))
+    })();
+    write_read_until(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size input_len
+/// - Requires a valid pointer to a utf8 buffer of size until_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Writes the input, then reads until `until` appears in the output, the process exits or the timeout elapses
+/// The result is everything read so far
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+/// Returns 99 on process exit
+#[no_mangle]
+pub unsafe extern "C" fn pty_exchange(
+    this: *mut Pty,
+    input: *const u8,
+    input_len: usize,
+    until: *const u8,
+    until_len: usize,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let read = (|| {
+        let input = std::slice::from_raw_parts(input, input_len).to_vec();
+        let until = std::str::from_utf8(std::slice::from_raw_parts(until, until_len))?;
+        this.exchange(input, until, Duration::from_millis(timeout_ms))
+    })();
+    write_read_until(read, result)
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the data pointer to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the data length to
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the exit code to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Reads all the output until the process exits, a negative timeout waits forever
+/// The data is utf8 and isn't null terminated, it has to be freed with pty_free_bytes
+///
+/// Returns -1 on error
+/// Returns 1 on timeout, the data is the output read so far and the exit code isn't written
+#[no_mangle]
+pub unsafe extern "C" fn pty_collect(
+    this: *mut Pty,
+    timeout_ms: i64,
+    data: *mut usize,
+    data_len: *mut usize,
+    exit_code: *mut i32,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    match this.collect(timeout) {
+        Ok((output, code)) => {
+            let output = output.into_bytes().into_boxed_slice();
+            *data_len = output.len();
+            *data = Box::into_raw(output) as *mut u8 as _;
+            match code {
+                Some(code) => {
+                    *exit_code = code as i32;
+                    0
+                }
+                None => 1,
+            }
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the data pointer to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the data length to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Copies all the output of the process so far, requires record
+/// The data has to be freed with pty_free_bytes
+/// The data is the raw output and isn't null terminated
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_transcript(
+    this: *mut Pty,
+    data: *mut usize,
+    data_len: *mut usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.transcript() {
+        Ok(transcript) => {
+            let transcript = transcript.into_boxed_slice();
+            *data_len = transcript.len();
+            *data = Box::into_raw(transcript) as *mut u8 as _;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a data pointer and its length written by pty_collect or pty_transcript
+/// - The data can't be used after
+#[no_mangle]
+pub unsafe extern "C" fn pty_free_bytes(data: *mut u8, data_len: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        data, data_len,
+    )));
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires 2 valid pointers to buffers of size 8
+///   to write the number of bytes read and written to
+///
+/// Returns the number of bytes read from the process and written to it since the pty was created
+#[no_mangle]
+pub unsafe extern "C" fn pty_stats(
+    this: *mut Pty,
+    read_bytes: *mut u64,
+    written_bytes: *mut u64,
+) -> i8 {
+    let this = unsafe { &*this };
+    *read_bytes = this.stats.read_bytes.load(Ordering::Relaxed);
+    *written_bytes = this.stats.written_bytes.load(Ordering::Relaxed);
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the number of bytes to
+///
+/// Returns the number of bytes that were read from the process but not consumed by pty_read yet
+#[no_mangle]
+pub unsafe extern "C" fn pty_pending_bytes(this: *mut Pty, bytes: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    *bytes = this.pending_bytes();
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 4
+///   to write the fd to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Returns a fd that is readable while pty_read has something to return, so it can be registered
+/// in an event loop instead of polling. It's cleared by the reads, on windows it's an event handle
+/// that is signaled instead
+///
+/// The fd is owned by the pty, it's closed by pty_close
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_readable_fd(this: *mut Pty, fd: *mut i32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.reader.readable_fd() {
+        Ok(readable_fd) => {
+            *fd = readable_fd;
+            0
+        }
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
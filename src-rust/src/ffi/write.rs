@@ -0,0 +1,328 @@
+use crate::{utils::boxed_error_to_cstring, Pty};
+use std::{ffi::CString, mem::ManuallyDrop, os::raw::c_char, time::Duration};
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to data encoded as Cstring
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_write(this: *mut Pty, data: *mut c_char, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    let data = ManuallyDrop::new(CString::from_raw(data));
+    match (|| {
+        let data_str = data.to_str()?.to_owned(); // NOTE: can we send str in the channels ?
+        this.write(data_str)
+    })() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size data_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the number of queued bytes to
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_write for data that isn't utf8
+/// The writes are all or nothing for now, so the count is data_len on success and 0 on error,
+/// it's reported so the callers don't need to change if partial writes are added
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_write_bytes(
+    this: *mut Pty,
+    data: *const u8,
+    data_len: usize,
+    written: *mut usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let data = std::slice::from_raw_parts(data, data_len).to_vec();
+    match this.write_bytes(data) {
+        Ok(()) => {
+            *written = data_len;
+            0
+        }
+        Err(err) => {
+            *written = 0;
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a utf8 buffer of size path_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Writes the contents of the file to the pty without passing them through the ffi, for big inputs
+/// The file is streamed by the writer thread, pty_flush waits until it's written
+/// A read error stops the write and is returned by the next read
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_write_file(
+    this: *mut Pty,
+    path: *const u8,
+    path_len: usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match (|| {
+        this.write_file(std::str::from_utf8(std::slice::from_raw_parts(
+            path, path_len,
+        ))?)
+    })() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to data encoded as Cstring
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the result to
+///
+/// Same as pty_write but appends the line ending of the command, \n by default or \r
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_write_line(
+    this: *mut Pty,
+    data: *mut c_char,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let data = ManuallyDrop::new(CString::from_raw(data));
+    match (|| this.write_line(data.to_str()?.to_owned()))() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size data_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Queues the data without blocking, for callers that do their own flow control
+/// The write channel is only full with write_channel_capacity
+///
+/// Returns -1 on error
+/// Returns 1 if the write channel is full, nothing is queued then
+#[no_mangle]
+pub unsafe extern "C" fn pty_try_write(
+    this: *mut Pty,
+    data: *const u8,
+    data_len: usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let data = std::slice::from_raw_parts(data, data_len).to_vec();
+    match this.try_write(data) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size data_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Writes the data and waits until it's written to the pty or the timeout elapses
+/// On timeout the data stays queued
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+#[no_mangle]
+pub unsafe extern "C" fn pty_write_timeout(
+    this: *mut Pty,
+    data: *const u8,
+    data_len: usize,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let data = std::slice::from_raw_parts(data, data_len).to_vec();
+    match this.write_timeout(data, Duration::from_millis(timeout_ms)) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size data_len
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Writes the data wrapped in the bracketed paste sequences \e[200~ and \e[201~
+/// The end sequence is removed from the data
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_write_paste(
+    this: *mut Pty,
+    data: *const u8,
+    data_len: usize,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    let data = std::slice::from_raw_parts(data, data_len);
+    match this.write_paste(data) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the count to
+///
+/// Returns the number of writes that are queued and not written to the pty yet
+#[no_mangle]
+pub unsafe extern "C" fn pty_pending_writes(this: *mut Pty, count: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    *count = this.tx_write.len();
+    0
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Writes the control character of `ch`, for example 'C' writes Ctrl-C
+/// ch is a unicode code point, so a character that doesn't have a control character isn't truncated to one that does
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_send_control(this: *mut Pty, ch: u32, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.send_control(ch) {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Blocks until all the previous writes are written to the pty
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_flush(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.flush() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Same as pty_flush but gives up after timeout_ms, the data stays queued then
+///
+/// Returns -1 on error
+/// Returns 1 on timeout
+#[no_mangle]
+pub unsafe extern "C" fn pty_flush_timeout(
+    this: *mut Pty,
+    timeout_ms: u64,
+    result: *mut usize,
+) -> i8 {
+    let this = unsafe { &*this };
+    match this.flush_timeout(Some(Duration::from_millis(timeout_ms))) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Allows writing again after pty_close_stdin, unix only
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_reopen_stdin(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.reopen_stdin() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
+
+/// # Safety
+/// - Requires a valid pointer to a Pty
+/// - Requires a valid pointer to a buffer of size 8
+///   to write the error to
+///
+/// Closes the input of the process (sends EOF), the pty can still be read from
+///
+/// Returns -1 on error
+#[no_mangle]
+pub unsafe extern "C" fn pty_close_stdin(this: *mut Pty, result: *mut usize) -> i8 {
+    let this = unsafe { &*this };
+    match this.close_stdin() {
+        Ok(()) => 0,
+        Err(err) => {
+            *result = boxed_error_to_cstring(err).into_raw() as _;
+            -1
+        }
+    }
+}
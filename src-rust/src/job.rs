@@ -0,0 +1,56 @@
+use crate::Result;
+use std::ptr;
+use winapi::{
+    shared::minwindef::FALSE,
+    um::{
+        handleapi::CloseHandle,
+        jobapi2::{AssignProcessToJobObject, CreateJobObjectW, TerminateJobObject},
+        processthreadsapi::OpenProcess,
+        winnt::{HANDLE, PROCESS_SET_QUOTA, PROCESS_TERMINATE},
+    },
+};
+
+/// A job object the process is assigned to, the processes it starts join it too
+///
+/// Killing the process only kills the direct child, terminating the job kills the whole tree
+pub struct Job(HANDLE);
+// the handle can be used from any thread
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+impl Job {
+    /// Creates a job and assigns the process to it
+    ///
+    /// NOTE: the processes started before the assignment are not part of the job
+    pub fn new(pid: u32) -> Result<Self> {
+        let job = unsafe { CreateJobObjectW(ptr::null_mut(), ptr::null()) };
+        if job.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let job = Self(job);
+        let process = unsafe { OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, FALSE, pid) };
+        if process.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let assigned = unsafe { AssignProcessToJobObject(job.0, process) };
+        unsafe { CloseHandle(process) };
+        if assigned == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(job)
+    }
+
+    /// Kills all the processes of the job
+    pub fn terminate(&self) -> Result<()> {
+        if unsafe { TerminateJobObject(self.0, 1) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
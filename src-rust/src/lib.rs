@@ -1,358 +1,2304 @@
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{
+    bounded, select, unbounded, Receiver, RecvTimeoutError, SendTimeoutError, Sender, TryRecvError,
+    TrySendError,
+};
+use parking_lot::{Condvar, Mutex};
 use portable_pty::{
-    native_pty_system, ChildKiller as Ck, CommandBuilder, MasterPty, PtySize, SlavePty,
+    native_pty_system, ChildKiller as Ck, CommandBuilder, ExitStatus, MasterPty, PtyPair, PtySize,
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{cell::Cell, ffi::CString, io::Read, mem::ManuallyDrop, time::Duration};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    io::Read,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+mod cast;
+mod error;
+mod ffi;
+#[cfg(windows)]
+mod job;
+mod log;
+mod ready;
+#[cfg(unix)]
+mod stderr;
+#[cfg(unix)]
+mod termios;
 mod utils;
-use std::os::raw::c_char;
-use utils::{boxed_error_to_cstring, cstr_to_type, type_to_cstr};
+use error::{ErrorKind, PtyError};
+pub use ffi::*;
+use log::{log, LogLevel};
+use ready::Ready;
+use utils::decode_utf8;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub struct Pty {
     reader: PtyReader,
-    tx_write: Sender<String>,
+    // set when the stderr is separated from the pty
+    stderr: Option<PtyReader>,
+    tx_write: Sender<WriteMessage>,
+    // why the writer thread stopped, writes fail after it's set
+    writer_closed: Arc<Mutex<Option<PtyError>>>,
     // keep the slave alive
     // so windows works
     // https://github.com/wez/wezterm/issues/4206
-    _slave: Box<dyn SlavePty + Send>,
+    // on unix it's closed after spawning, so the reader gets EOF once the process exits
+    #[cfg(windows)]
+    _slave: Box<dyn portable_pty::SlavePty + Send>,
     master: Box<dyn MasterPty + Send>,
-    // use to end the spawned process
-    ck: Box<dyn Ck>,
+    // use to end the spawned process, behind a lock since the ffi functions only share the pty
+    ck: Mutex<Box<dyn Ck>>,
+    pid: Option<u32>,
+    // used to kill the process tree, None if the process couldn't be assigned to a job
+    #[cfg(windows)]
+    job: Option<job::Job>,
+    // sent by pty_close instead of killing the process tree
+    close_signal: Option<i32>,
+    // the detached command is in the process group of the process, so it isn't killed on close
+    detached: bool,
+    // appended by write_line
+    line_ending: LineEnding,
+    // the writes are split in chunks of this size
+    write_chunk_size: usize,
+    // all the output of the process, set when recording
+    transcript: Option<Arc<Transcript>>,
+    stats: Arc<Stats>,
+    // the last pattern compiled by read_until_regex, prompts are usually waited for repeatedly
+    last_regex: Mutex<Option<Regex>>,
+    // set by the waiter thread once the child exits
+    exit: Arc<Exit>,
 }
 
-#[derive(Clone)]
-struct PtyReader {
-    rx_read: Receiver<Message>,
-    done: Cell<bool>,
+/// The bytes transferred since the pty was created, updated by the background threads
+#[derive(Default)]
+struct Stats {
+    // the output of the pty and the separated stderr
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+}
+
+/// The raw output of the process, kept for debugging
+struct Transcript {
+    data: Mutex<Vec<u8>>,
+    // the oldest bytes are dropped when it's exceeded
+    max_bytes: Option<usize>,
+}
+impl Transcript {
+    fn new(max_bytes: Option<usize>) -> Self {
+        Self {
+            data: Mutex::new(Vec::new()),
+            max_bytes,
+        }
+    }
+    fn push(&self, bytes: &[u8]) {
+        let mut data = self.data.lock();
+        data.extend_from_slice(bytes);
+        if let Some(max_bytes) = self.max_bytes {
+            let excess = data.len().saturating_sub(max_bytes);
+            data.drain(..excess);
+        }
+    }
+}
+
+/// Called with the pid and the exit code once the process exits, the code is -1 if it's unknown
+pub type ExitCallback = extern "C" fn(pid: u32, code: i32);
+
+#[derive(Default)]
+struct Exit {
+    pid: Option<u32>,
+    status: Mutex<Option<std::result::Result<ExitStatus, String>>>,
+    // the signal that terminated the process, only available on unix
+    signal: Mutex<Option<i32>>,
+    // why the pty killed the process, reported before the End message
+    killed: Mutex<Option<PtyError>>,
+    end_sent: AtomicBool,
+    exited: Condvar,
+    // locked while the status is set, so the callback is called exactly once
+    callback: Mutex<Option<ExitCallback>>,
+}
+impl Exit {
+    fn set(&self, status: std::result::Result<ExitStatus, String>, signal: Option<i32>) {
+        let slot = self.callback.lock();
+        let code = Self::callback_code(&status);
+        *self.signal.lock() = signal;
+        *self.status.lock() = Some(status);
+        self.exited.notify_all();
+        // don't hold the lock while calling the callback
+        let callback = *slot;
+        drop(slot);
+        if let Some(callback) = callback {
+            callback(self.pid.unwrap_or_default(), code);
+        }
+    }
+    /// Calls the callback right away if the process already exited
+    fn set_callback(&self, callback: Option<ExitCallback>) {
+        let mut slot = self.callback.lock();
+        *slot = callback;
+        let code = self.status.lock().as_ref().map(Self::callback_code);
+        drop(slot);
+        if let (Some(callback), Some(code)) = (callback, code) {
+            callback(self.pid.unwrap_or_default(), code);
+        }
+    }
+    fn callback_code(status: &std::result::Result<ExitStatus, String>) -> i32 {
+        match status {
+            Ok(status) => status.exit_code() as i32,
+            Err(_) => -1,
+        }
+    }
+    fn exited(&self) -> bool {
+        self.status.lock().is_some()
+    }
+    /// Sends the reason the process was killed and End, only the first call sends them
+    fn send_end(&self, tx_read: &ReadSender) {
+        if self.end_sent.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(err) = self.killed.lock().take() {
+            let _ = tx_read.send((Instant::now(), Message::Error(err)));
+        }
+        let _ = tx_read.send((Instant::now(), Message::End));
+    }
+    /// Kills the process, the reason is reported by the reader before the exit
+    fn kill(&self, ck: &mut dyn Ck, reason: PtyError) {
+        log(LogLevel::Warn, &reason.message);
+        *self.killed.lock() = Some(reason);
+        if let Err(err) = ck.kill() {
+            log(
+                LogLevel::Error,
+                &format!("failed to kill the process: {err}"),
+            );
+        }
+    }
+    /// Blocks until the process exits, a None timeout waits forever
+    ///
+    /// Returns false on timeout
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let mut status = self.status.lock();
+        match timeout {
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                while status.is_none() {
+                    if self.exited.wait_until(&mut status, deadline).timed_out() {
+                        return status.is_some();
+                    }
+                }
+            }
+            None => {
+                while status.is_none() {
+                    self.exited.wait(&mut status);
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The messages peeked by a reader
+///
+/// They are taken out of the channel, so they belong to that reader and its clones don't get them
+#[derive(Default)]
+struct Peeked(Mutex<VecDeque<TimedMessage>>);
+impl Clone for Peeked {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Messages taken out of the channel by the ring buffer, they are older than the ones left in it
+type Held = Arc<Mutex<VecDeque<TimedMessage>>>;
+
+/// The readers of a pty share the output, each chunk is returned by only one of them
+///
+/// The state is behind atomics and locks, a blocking read runs on another thread while the
+/// host can still call the non blocking functions
+pub struct PtyReader {
+    rx_read: Receiver<TimedMessage>,
+    done: AtomicBool,
+    // bytes sent by the reader thread that are not consumed yet
+    pending_bytes: Arc<AtomicUsize>,
+    // bytes dropped since the last read because of max_buffered_bytes
+    dropped_bytes: Arc<AtomicUsize>,
+    // readable while there are messages in the channel
+    ready: Arc<Ready>,
+    // an error sent by the background threads that is returned by the next read
+    error: Mutex<Option<PtyError>>,
+    // data that didn't fit in the buffer passed to read_into
+    leftover: Mutex<Vec<u8>>,
+    // messages taken from the channel by peek, returned by the next read
+    peeked: Peeked,
+    // when the pty was created
+    started: Instant,
+    // when the data returned by the last read was received by the reader thread
+    last_data_at: Mutex<Option<Instant>>,
+    // wakes up a blocked read, shared with the clones
+    interrupt: (Sender<()>, Receiver<()>),
+    // read before the channel, shared with the clones
+    held: Held,
+}
+impl Clone for PtyReader {
+    fn clone(&self) -> Self {
+        Self {
+            rx_read: self.rx_read.clone(),
+            done: AtomicBool::new(self.done.load(Ordering::Relaxed)),
+            pending_bytes: self.pending_bytes.clone(),
+            dropped_bytes: self.dropped_bytes.clone(),
+            ready: self.ready.clone(),
+            error: Mutex::new(self.error.lock().clone()),
+            leftover: Mutex::new(self.leftover.lock().clone()),
+            peeked: self.peeked.clone(),
+            started: self.started,
+            last_data_at: Mutex::new(*self.last_data_at.lock()),
+            interrupt: self.interrupt.clone(),
+            held: self.held.clone(),
+        }
+    }
 }
 impl PtyReader {
-    fn new(rx_read: Receiver<Message>) -> PtyReader {
+    fn new(
+        rx_read: Receiver<TimedMessage>,
+        pending_bytes: Arc<AtomicUsize>,
+        dropped_bytes: Arc<AtomicUsize>,
+        ready: Arc<Ready>,
+        held: Held,
+        started: Instant,
+    ) -> PtyReader {
         Self {
             rx_read,
-            done: Cell::new(false),
+            done: AtomicBool::new(false),
+            pending_bytes,
+            dropped_bytes,
+            ready,
+            error: Mutex::new(None),
+            leftover: Mutex::new(Vec::new()),
+            peeked: Peeked::default(),
+            started,
+            last_data_at: Mutex::new(None),
+            interrupt: bounded(1),
+            held,
         }
     }
+    /// Whether the next read returns End, there is nothing left to read
+    fn ended(&self) -> bool {
+        self.done.load(Ordering::Relaxed) && self.error.lock().is_none()
+    }
+
     //NOTE: this function should not block
     fn read(&self) -> Result<Message> {
-        if self.done.get() {
+        if let Some(err) = self.error.lock().take() {
+            return Err(err.into());
+        }
+        if self.done.load(Ordering::Relaxed) {
             return Ok(Message::End);
         }
 
-        let mut msgs: Vec<_> = self.rx_read.try_iter().collect();
+        // cleared before draining the channel, so a message sent after it sets it again
+        self.ready.clear();
+        let mut msgs: Vec<_> = std::mem::take(&mut *self.peeked.0.lock()).into();
+        self.take_held(&mut msgs);
+        if msgs.is_empty() {
+            match self.rx_read.try_recv() {
+                Ok(msg) => msgs.push(msg),
+                // all senders are gone without sending End, for example a background thread panicked
+                // report the end instead of returning empty data forever
+                Err(TryRecvError::Disconnected) => msgs.push((Instant::now(), Message::End)),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        self.process(msgs)
+    }
 
-        if msgs.contains(&Message::End) {
-            self.done.set(true);
+    /// Blocks until data is available, the process exits or the timeout elapses
+    ///
+    /// Returns None on timeout
+    fn read_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        if let Some(err) = self.error.lock().take() {
+            return Err(err.into());
+        }
+        if self.done.load(Ordering::Relaxed) {
+            return Ok(Some(Message::End));
+        }
 
-            // NOTE: We received the END message, this means that the process has exited
-            // But there could be some pending messages in the read channel, this is especisally true in windows
-            // So sleep a bit and check the channel again
-            std::thread::sleep(Duration::from_millis(100));
-            msgs.extend(self.rx_read.try_iter());
+        self.ready.clear();
+        let mut msgs: Vec<_> = std::mem::take(&mut *self.peeked.0.lock()).into();
+        msgs.extend(self.held.lock().drain(..));
+        if msgs.is_empty() {
+            msgs.push(select! {
+                recv(self.rx_read) -> msg => match msg {
+                    Ok(msg) => msg,
+                    // all senders are gone, so the process has exited
+                    Err(_) => (Instant::now(), Message::End),
+                },
+                recv(self.interrupt.1) -> _ => {
+                    return Err(PtyError::new(ErrorKind::Interrupted, "the read was interrupted").into())
+                }
+                default(timeout) => return Ok(None),
+            });
+        }
+        self.take_held(&mut msgs);
+        self.process(msgs).map(Some)
+    }
+
+    /// Appends the held messages and then the ones in the channel
+    fn take_held(&self, msgs: &mut Vec<TimedMessage>) {
+        // locked while draining the channel, so the ring buffer doesn't hold a message in between
+        let mut held = self.held.lock();
+        msgs.extend(held.drain(..));
+        msgs.extend(self.rx_read.try_iter());
+    }
+
+    /// Wakes up a read blocked in read_timeout, if none is blocked the next one returns right away
+    ///
+    /// Only one read is interrupted per call
+    fn interrupt(&self) {
+        // a pending interrupt already wakes up the next read
+        let _ = self.interrupt.0.try_send(());
+    }
+
+    /// Returns the buffered data without consuming it, the next read returns it
+    ///
+    /// Also returns whether the process exited after this data
+    fn peek(&self) -> (String, bool) {
+        let mut peeked = self.peeked.0.lock();
+        let mut msgs = Vec::new();
+        self.take_held(&mut msgs);
+        peeked.extend(msgs);
+        let mut data = String::new();
+        let mut ended = self.done.load(Ordering::Relaxed);
+        for (_, msg) in peeked.iter() {
+            match msg {
+                Message::Data(new_data) => data.push_str(new_data),
+                Message::End => ended = true,
+                // the data after the error is returned after it
+                Message::Error(_) => break,
+            }
+        }
+        (data, ended)
+    }
+
+    /// Same as read but copies the data into `buf`, the data that doesn't fit is returned by the next call
+    ///
+    /// Returns None on process exit
+    fn read_into(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let mut leftover = self.leftover.lock();
+        if leftover.is_empty() {
+            match self.read()? {
+                Message::Data(data) => *leftover = data.into_bytes(),
+                Message::End => return Ok(None),
+                Message::Error(err) => return Err(err.into()),
+            }
+        }
+        let n = leftover.len().min(buf.len());
+        buf[..n].copy_from_slice(&leftover[..n]);
+        leftover.drain(..n);
+        Ok(Some(n))
+    }
+
+    /// Reads until `needle` appears in the output, the process exits or the timeout elapses
+    ///
+    /// Returns everything read so far
+    fn read_until(&self, needle: &str, timeout: Duration) -> Result<(String, ReadUntil)> {
+        let mut search_from = 0;
+        self.read_until_with(timeout, |data| {
+            if data[search_from..].contains(needle) {
+                return true;
+            }
+            // the needle can start in the already searched data
+            search_from = data.len().saturating_sub(needle.len());
+            while !data.is_char_boundary(search_from) {
+                search_from -= 1;
+            }
+            false
+        })
+    }
+
+    /// Same as read_until but waits for a match of `regex`, it's matched against all the data read so far
+    fn read_until_regex(&self, regex: &Regex, timeout: Duration) -> Result<(String, ReadUntil)> {
+        self.read_until_with(timeout, |data| regex.is_match(data))
+    }
+
+    /// Reads until `found` returns true for the data read so far, the process exits or the timeout elapses
+    ///
+    /// On error the data read so far is put back, the next read returns it
+    fn read_until_with(
+        &self,
+        timeout: Duration,
+        mut found: impl FnMut(&str) -> bool,
+    ) -> Result<(String, ReadUntil)> {
+        let deadline = Instant::now() + timeout;
+        let mut data = String::new();
+        loop {
+            if found(&data) {
+                return Ok((data, ReadUntil::Found));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let msg = match self.read_timeout(remaining) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    self.unread(data);
+                    return Err(err);
+                }
+            };
+            match msg {
+                Some(Message::Data(new_data)) => data.push_str(&new_data),
+                Some(Message::End) => return Ok((data, ReadUntil::End)),
+                Some(Message::Error(err)) => {
+                    self.unread(data);
+                    return Err(err.into());
+                }
+                None => return Ok((data, ReadUntil::Timeout)),
+            }
+        }
+    }
+
+    /// Puts the data back in front of the output, so the next read returns it
+    fn unread(&self, data: String) {
+        if data.is_empty() {
+            return;
+        }
+        // it's counted again when it's read
+        self.pending_bytes.fetch_add(data.len(), Ordering::Relaxed);
+        self.peeked
+            .0
+            .lock()
+            .push_front((Instant::now(), Message::Data(data)));
+        self.ready.set();
+    }
+
+    fn process(&self, mut msgs: Vec<TimedMessage>) -> Result<Message> {
+        let dropped = self.dropped_bytes.swap(0, Ordering::Relaxed);
+        if msgs.iter().any(|(_, msg)| *msg == Message::End) {
+            self.done.store(true, Ordering::Relaxed);
 
-            if msgs.len() == 1 {
+            // NOTE: End is sent after the reader thread reached EOF, so all the output is already in the channel
+            if msgs.len() == 1 && dropped == 0 {
+                // every next read returns End
+                self.ready.set();
                 return Ok(Message::End);
             }
 
             // we might have some msgs here
             // we should send them to the user
-            msgs.retain(|msg| !matches!(msg, Message::End));
+            msgs.retain(|(_, msg)| !matches!(msg, Message::End));
+        }
+
+        let mut last_data_at = self.last_data_at.lock();
+        *last_data_at = None;
+        let mut msg = String::new();
+        for (at, m) in msgs {
+            match m {
+                Message::Data(data) => {
+                    // the time of the first chunk, it's when the returned data started arriving
+                    last_data_at.get_or_insert(at);
+                    msg.push_str(&data)
+                }
+                // keep the first error, it's the one that stopped the stream
+                Message::Error(err) => {
+                    self.error.lock().get_or_insert(err);
+                }
+                Message::End => unreachable!(),
+            }
+        }
+        drop(last_data_at);
+        self.pending_bytes.fetch_sub(msg.len(), Ordering::Relaxed);
+        // the dropped data is older than everything left in the channel
+        if dropped > 0 {
+            msg.insert_str(0, &format!("[{dropped} bytes dropped]\r\n"));
+        }
+
+        // return the data first, the error is returned by the next read
+        if msg.is_empty() {
+            if let Some(err) = self.error.lock().take() {
+                return Err(err.into());
+            }
         }
+        // the next read returns the error or End without a new message
+        if self.done.load(Ordering::Relaxed) || self.error.lock().is_some() {
+            self.ready.set();
+        }
+        Ok(Message::Data(msg))
+    }
+
+    /// Returns the readiness fd, it's readable while the next read has something to return
+    fn readable_fd(&self) -> Result<i32> {
+        let fd = self.ready.fd()?;
+        // the messages sent before the fd was created didn't set it
+        if !self.rx_read.is_empty()
+            || !self.peeked.0.lock().is_empty()
+            || !self.held.lock().is_empty()
+            || self.done.load(Ordering::Relaxed)
+            || self.error.lock().is_some()
+        {
+            self.ready.set();
+        }
+        Ok(fd)
+    }
+}
 
-        let msg = msgs
+/// Used when the current process doesn't have a PATH
+#[cfg(unix)]
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+#[cfg(windows)]
+const DEFAULT_PATH: &str = r"C:\Windows\system32;C:\Windows";
+
+#[derive(Serialize, Deserialize, Default)]
+struct Command {
+    cmd: String,
+    args: Vec<String>,
+    // applied in order, accepts a list of pairs or an object
+    #[serde(deserialize_with = "utils::deserialize_env")]
+    env: Vec<(String, String)>,
+    cwd: Option<String>,
+    // start from an empty environment instead of inheriting the parent one
+    clear_env: Option<bool>,
+    // the PATH of the process instead of the parent one, also used with clear_env
+    path: Option<String>,
+    // how long to wait for the end of the output after the process exits, defaults to 100ms
+    end_drain_ms: Option<u64>,
+    // how to handle output that isn't valid utf8, defaults to strict
+    decode: Option<Decode>,
+    // how many reads can be buffered before the reader thread blocks, defaults to 1024
+    read_channel_capacity: Option<usize>,
+    // how many writes can be queued before write blocks, unbounded by default
+    write_channel_capacity: Option<usize>,
+    // run cmd as a command line through a shell
+    shell: Option<Shell>,
+    // set TERM, LANG, COLUMNS and LINES defaults, defaults to true
+    default_env: Option<bool>,
+    // set COLORTERM=truecolor with the default variables
+    truecolor: Option<bool>,
+    // variables to remove from the environment, applied last
+    unset_env: Option<Vec<String>>,
+    // whether the terminal echoes the input back, unix only
+    echo: Option<bool>,
+    // whether the terminal translates \n to \r\n in the output, unix only
+    onlcr: Option<bool>,
+    // initial size of the pty, defaults to 24 rows and 80 cols
+    size: Option<PtySize>,
+    // redirect stderr to a pipe read with read_stderr instead of the pty, unix only
+    separate_stderr: Option<bool>,
+    // start the process as the leader of a new session and process group, unix only, defaults to true
+    setsid: Option<bool>,
+    // make the pty the controlling terminal of the process with TIOCSCTTY, unix only, defaults to true
+    set_controlling_terminal: Option<bool>,
+    // the umask of the process, unix only
+    umask: Option<u32>,
+    // the nice value of the process, mapped to a priority class on windows
+    nice: Option<i32>,
+    // kill the process if it's still running after this long
+    timeout_ms: Option<u64>,
+    // kill the process if it doesn't output anything for this long
+    idle_timeout_ms: Option<u64>,
+    // the signal sent to the process group on close, defaults to killing the process tree
+    close_signal: Option<i32>,
+    // keep all the output so it can be read with transcript
+    record: Option<bool>,
+    // only keep the last bytes of the transcript
+    record_max_bytes: Option<usize>,
+    // write the output to this file in the asciinema v2 format
+    record_path: Option<String>,
+    // drop the oldest unread output when there is more than this, instead of blocking the reader thread
+    max_buffered_bytes: Option<usize>,
+    // batch the output read during this window into one message, unix only
+    coalesce_ms: Option<u64>,
+    // the line ending appended by write_line, defaults to lf
+    line_ending: Option<LineEnding>,
+    // big writes are split in chunks of this size, defaults to 4096
+    write_chunk_size: Option<usize>,
+    // run the command through a /bin/sh that stops itself with SIGSTOP before exec, unix only
+    start_stopped: Option<bool>,
+    // file descriptors to keep open in the process, not supported yet
+    inherit_fds: Option<Vec<i32>>,
+    // the argv[0] of the process instead of cmd, not supported yet
+    arg0: Option<String>,
+    // start the command in the background so it keeps running after the pty is closed, unix only
+    detach: Option<bool>,
+    // the pty implementation on windows, defaults to conpty
+    backend: Option<Backend>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Shell {
+    // use the default shell: /bin/sh on unix and cmd.exe on windows
+    Enabled(bool),
+    // path to the shell to use
+    Path(String),
+}
+
+impl Command {
+    /// The checks that don't depend on the system, the platforms fail in different and confusing ways on these
+    fn validate(&self) -> Result<()> {
+        if self.cmd.is_empty() {
+            return Err(PtyError::new(ErrorKind::InvalidInput, "empty command").into());
+        }
+        if let Some(arg) = std::iter::once(&self.cmd)
+            .chain(&self.args)
+            .find(|arg| arg.contains('\0'))
+        {
+            return Err(PtyError::new(
+                ErrorKind::InvalidString,
+                format!("the command contains a null byte: {arg:?}"),
+            )
+            .into());
+        }
+        for (key, value) in &self.env {
+            if key.is_empty() || key.contains('=') {
+                return Err(PtyError::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid environment variable name: {key:?}"),
+                )
+                .into());
+            }
+            if key.contains('\0') || value.contains('\0') {
+                return Err(PtyError::new(
+                    ErrorKind::InvalidString,
+                    format!("the environment variable {key:?} contains a null byte"),
+                )
+                .into());
+            }
+        }
+
+        // portable_pty always calls setsid and makes the pty the controlling terminal before exec
+        #[cfg(unix)]
+        if self.setsid == Some(false) {
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "setsid can't be disabled, the process always starts in a new session",
+            )
+            .into());
+        }
+        // portable_pty only implements conpty since it dropped winpty
+        #[cfg(windows)]
+        if self.backend == Some(Backend::Winpty) {
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "the winpty backend is not supported, only conpty is available",
+            )
+            .into());
+        }
+        #[cfg(unix)]
+        if self.set_controlling_terminal == Some(false) {
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "set_controlling_terminal can't be disabled, the pty is always the controlling terminal",
+            )
+            .into());
+        }
+        // portable_pty closes all the file descriptors above 2 in the child before exec, so clearing
+        // CLOEXEC isn't enough and there is no hook to skip some of them
+        if self.inherit_fds.as_ref().is_some_and(|fds| !fds.is_empty()) {
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "inherit_fds is not supported, the file descriptors above 2 are closed before exec",
+            )
+            .into());
+        }
+        // portable_pty always passes the program as argv[0], and /bin/sh might not support exec -a
+        if self.arg0.is_some() {
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "arg0 is not supported, the program is always passed as argv[0]",
+            )
+            .into());
+        }
+        #[cfg(windows)]
+        for (enabled, option) in [
+            (self.separate_stderr, "separate_stderr"),
+            (self.detach, "detach"),
+            (self.start_stopped, "start_stopped"),
+        ] {
+            if enabled.unwrap_or(false) {
+                return Err(PtyError::new(
+                    ErrorKind::Unsupported,
+                    format!("{option} is not supported on windows"),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The working directory of the process, relative paths are resolved against the current directory
+    fn resolve_cwd(&self) -> Result<PathBuf> {
+        let cwd = match &self.cwd {
+            Some(cwd) => std::env::current_dir()?.join(cwd),
+            None => std::env::current_dir()?,
+        };
+        match std::fs::metadata(&cwd) {
+            Ok(metadata) if metadata.is_dir() => Ok(cwd),
+            Ok(_) => Err(PtyError::new(
+                ErrorKind::NotFound,
+                format!("cwd is not a directory: {}", cwd.display()),
+            )
+            .into()),
+            Err(_) => Err(PtyError::new(
+                ErrorKind::NotFound,
+                format!("cwd does not exist: {}", cwd.display()),
+            )
+            .into()),
+        }
+    }
+
+    /// The PATH the process starts with, following the same steps as the environment in spawn
+    fn resolve_path(&self) -> Option<OsString> {
+        let mut path = if self.clear_env.unwrap_or(false) {
+            None
+        } else {
+            Some(std::env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into()))
+        };
+        if let Some(custom) = &self.path {
+            path = Some(custom.into());
+        }
+        for (key, value) in &self.env {
+            if utils::is_path_key(key) {
+                path = Some(value.into());
+            }
+        }
+        if self
+            .unset_env
             .iter()
-            .map(|msg| {
-                if let Message::Data(data) = msg {
-                    data.as_str()
+            .flatten()
+            .any(|key| utils::is_path_key(key))
+        {
+            path = None;
+        }
+        path
+    }
+
+    /// Checks the command without starting it: the options, cwd and that the program can be found
+    fn check(&self) -> Result<()> {
+        self.validate()?;
+        let cwd = self.resolve_cwd()?;
+        let program = match &self.shell {
+            None | Some(Shell::Enabled(false)) => self.cmd.as_str(),
+            Some(Shell::Path(path)) => path.as_str(),
+            Some(Shell::Enabled(true)) if cfg!(windows) => "cmd.exe",
+            Some(Shell::Enabled(true)) => "/bin/sh",
+        };
+        if utils::find_program(program, self.resolve_path().as_deref(), &cwd).is_none() {
+            return Err(PtyError::new(
+                ErrorKind::NotFound,
+                format!("command not found: {program}"),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Decode {
+    // invalid utf8 stops the output
+    Strict,
+    // invalid utf8 is replaced with U+FFFD
+    Lossy,
+    // each invalid byte is replaced with \xNN
+    Hex,
+}
+
+/// The size passed to pty_resize, the pixel size defaults to the current one
+#[derive(Deserialize)]
+struct ResizeSize {
+    rows: u16,
+    cols: u16,
+    pixel_width: Option<u16>,
+    pixel_height: Option<u16>,
+}
+
+/// The size used by pty_get_size_c and pty_resize_c, for hosts that don't parse JSON
+///
+/// The layout is part of the ffi api, don't change it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CPtySize {
+    pub rows: u16,
+    pub cols: u16,
+    pub pixel_width: u16,
+    pub pixel_height: u16,
+}
+impl From<PtySize> for CPtySize {
+    fn from(size: PtySize) -> Self {
+        Self {
+            rows: size.rows,
+            cols: size.cols,
+            pixel_width: size.pixel_width,
+            pixel_height: size.pixel_height,
+        }
+    }
+}
+impl From<CPtySize> for PtySize {
+    fn from(size: CPtySize) -> Self {
+        Self {
+            rows: size.rows,
+            cols: size.cols,
+            pixel_width: size.pixel_width,
+            pixel_height: size.pixel_height,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    Conpty,
+    // removed from portable_pty, selecting it is an error
+    Winpty,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum LineEnding {
+    // \n, what the terminal sends for enter in canonical mode after translating \r
+    Lf,
+    // \r, what a terminal sends for enter, some programs in raw mode only accept it
+    Cr,
+}
+
+enum WriteMessage {
+    Data(Vec<u8>),
+    // stream the contents of the file, the path is used in the errors
+    File(std::fs::File, String),
+    // flush the writer and acknowledge on the provided channel
+    Flush(Sender<std::io::Result<()>>),
+    // close the writer, the next writes will fail
+    Close,
+}
+
+enum ReadUntil {
+    Found,
+    Timeout,
+    End,
+}
+
+// a message with the time it was sent by the background threads
+type TimedMessage = (Instant, Message);
+
+/// Sends the messages of the background threads to the readers and sets their readiness fd
+#[derive(Clone)]
+struct ReadSender {
+    tx: Sender<TimedMessage>,
+    ready: Arc<Ready>,
+}
+impl ReadSender {
+    fn send(
+        &self,
+        msg: TimedMessage,
+    ) -> std::result::Result<(), crossbeam::channel::SendError<TimedMessage>> {
+        let result = self.tx.send(msg);
+        self.ready.set();
+        result
+    }
+}
+
+/// The byte sent by pressing Ctrl with the character `ch`, for example `C` is 0x03
+fn control_byte(ch: u32) -> Result<u8> {
+    match u8::try_from(ch).map(|ch| ch.to_ascii_uppercase()) {
+        Ok(ch @ b'@'..=b'_') => Ok(ch ^ 0x40),
+        Ok(b'?') => Ok(0x7f),
+        _ => Err(PtyError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{:?} doesn't have a control character",
+                char::from_u32(ch).unwrap_or(char::REPLACEMENT_CHARACTER)
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Drops the oldest output when the unread output is bigger than max_bytes
+struct RingBuffer {
+    rx_read: Receiver<TimedMessage>,
+    max_bytes: usize,
+    dropped_bytes: Arc<AtomicUsize>,
+    // the End and Error messages taken out of the channel, shared with the readers
+    held: Held,
+}
+impl RingBuffer {
+    fn trim(&self, pending_bytes: &AtomicUsize) {
+        // locked while taking the messages, so a reader doesn't get the newer ones before them
+        let mut held = self.held.lock();
+        while pending_bytes.load(Ordering::Relaxed) > self.max_bytes {
+            match self.rx_read.try_recv() {
+                Ok((_, Message::Data(data))) => {
+                    pending_bytes.fetch_sub(data.len(), Ordering::Relaxed);
+                    self.dropped_bytes.fetch_add(data.len(), Ordering::Relaxed);
+                }
+                // only data is dropped, the other messages are kept in front of the newer output
+                Ok(msg) => held.push_back(msg),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Message {
+    Data(String),
+    // a background thread failed
+    Error(PtyError),
+    End,
+}
+
+/// Batches the output read during a window into one message, unix only
+#[cfg(unix)]
+struct Coalesce {
+    window: Duration,
+    // a copy of the pty fd, polled to know if the next read would block
+    fd: std::os::fd::OwnedFd,
+}
+#[cfg(unix)]
+impl Coalesce {
+    // the batch is sent once it's this big, even if the window didn't elapse
+    const MAX_BYTES: usize = 64 * 1024;
+
+    /// Returns false if the fd isn't readable before the deadline
+    fn wait_readable(&self, deadline: Instant) -> bool {
+        use std::os::fd::AsRawFd;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+                0 => return false,
+                -1 if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {
+                }
+                // the read reports the errors
+                _ => return true,
+            }
+        }
+    }
+}
+// the pty can't be polled on windows, so the output isn't batched
+#[cfg(windows)]
+enum Coalesce {}
+
+/// Reads the output until EOF or an error, the errors are sent to the channel
+///
+/// `on_data` is called with the raw bytes of each read
+fn read_loop(
+    reader: &mut dyn Read,
+    tx_read: &ReadSender,
+    decode: Decode,
+    pending_bytes: &AtomicUsize,
+    ring: Option<&RingBuffer>,
+    coalesce: Option<&Coalesce>,
+    on_data: &mut dyn FnMut(&[u8]),
+) {
+    let send = |at: Instant, data: String| {
+        // count before sending so the reader never sees more bytes than counted
+        pending_bytes.fetch_add(data.len(), Ordering::Relaxed);
+        tx_read.send((at, Message::Data(data))).ok(); // the sender closed (the program finished ?);
+        if let Some(ring) = ring {
+            ring.trim(pending_bytes);
+        }
+    };
+    let mut buf = [0; 512];
+    // bytes of a utf8 sequence that was split between reads
+    let mut pending = Vec::new();
+    // the output batched by coalesce and when it started
+    let mut batch = String::new();
+    let mut batch_started = None;
+    loop {
+        #[cfg(unix)]
+        if let (Some(coalesce), Some(started)) = (coalesce, batch_started) {
+            if !coalesce.wait_readable(started + coalesce.window) {
+                send(started, std::mem::take(&mut batch));
+                batch_started = None;
+            }
+        }
+        #[cfg(windows)]
+        let _ = coalesce;
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            // linux returns EIO instead of EOF once the slave is closed
+            #[cfg(unix)]
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => 0,
+            // a signal arrived during the read, nothing was read
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            // the fd was made non-blocking, by the process for example since it's shared with the pty
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(err) => {
+                if let Some(started) = batch_started {
+                    send(started, batch);
+                }
+                let err = PtyError::new(ErrorKind::Io, format!("failed to read data: {err}"));
+                log(LogLevel::Error, &err.message);
+                tx_read.send((Instant::now(), Message::Error(err))).ok();
+                break;
+            }
+        };
+        if n == 0 {
+            if let Some(started) = batch_started {
+                send(started, batch);
+            }
+            // the pty has already exited
+            // so no need to send the end message?
+            break;
+        };
+        on_data(&buf[0..n]);
+        pending.extend_from_slice(&buf[0..n]);
+        let data = match decode_utf8(&mut pending, decode) {
+            Ok(data) => data,
+            Err(err) => {
+                if let Some(started) = batch_started {
+                    send(started, batch);
+                }
+                let err = PtyError::new(
+                    ErrorKind::InvalidString,
+                    format!("data is not valid utf8: {err}"),
+                );
+                log(LogLevel::Error, &err.message);
+                tx_read.send((Instant::now(), Message::Error(err))).ok();
+                break;
+            }
+        };
+        // the read only had the start of a character, the rest comes with the next read
+        if data.is_empty() {
+            continue;
+        }
+        #[cfg(unix)]
+        if coalesce.is_some() {
+            let started = *batch_started.get_or_insert_with(Instant::now);
+            batch.push_str(&data);
+            if batch.len() >= Coalesce::MAX_BYTES {
+                send(started, std::mem::take(&mut batch));
+                batch_started = None;
+            }
+            continue;
+        }
+        send(Instant::now(), data);
+    }
+}
+
+fn stdin_closed_error() -> PtyError {
+    PtyError::new(ErrorKind::Disconnected, "stdin was closed")
+}
+
+/// Runs the body of a background thread, a panic is sent to the channel as an error
+/// so the readers don't wait forever for output that never comes
+fn catch_panic<T>(
+    name: &str,
+    tx_read: &ReadSender,
+    f: impl FnOnce() -> T,
+) -> std::result::Result<T, PtyError> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let reason = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown reason");
+        let err = PtyError::new(
+            ErrorKind::Disconnected,
+            format!("the {name} thread panicked: {reason}"),
+        );
+        log(LogLevel::Error, &err.message);
+        tx_read
+            .send((Instant::now(), Message::Error(err.clone())))
+            .ok();
+        err
+    })
+}
+
+impl Pty {
+    fn create(command: Command) -> Result<Self> {
+        // NOTE: the size is set when opening the pty, so the process sees it from the start
+        let pair = Self::open(command.size.unwrap_or(PtySize {
+            rows: 24,
+            cols: 80,
+            // Not all systems support pixel_width, pixel_height,
+            // but it is good practice to set it to something
+            // that matches the size of the selected font.  That
+            // is more complex than can be shown here in this
+            // brief example though!
+            pixel_width: 0,
+            pixel_height: 0,
+        }))?;
+        // the size is already set
+        Self::spawn(
+            pair,
+            Command {
+                size: None,
+                ..command
+            },
+        )
+    }
+
+    /// Opens a pty without starting a process, the process is started with spawn
+    fn open(size: PtySize) -> Result<PtyPair> {
+        // Use the native pty implementation for the system
+        let pty_system = native_pty_system();
+        Ok(pty_system.openpty(size)?)
+    }
+
+    /// Starts the process in an opened pty
+    fn spawn(pair: PtyPair, command: Command) -> Result<Self> {
+        let started = Instant::now();
+
+        command.validate()?;
+        let cwd = command.resolve_cwd()?;
+
+        if let Some(size) = command.size {
+            pair.master.resize(size)?;
+        }
+        // before spawning, so a bad path doesn't leave a running process without a handle to kill it
+        let mut cast = match &command.record_path {
+            Some(path) => Some(cast::Cast::create(path, pair.master.get_size()?, started)?),
+            None => None,
+        };
+
+        // configure the terminal before the process starts so it sees the right settings from the beginning
+        #[cfg(unix)]
+        if command.echo.is_some() || command.onlcr.is_some() {
+            let fd = pair
+                .master
+                .as_raw_fd()
+                .ok_or("failed to get the pty file descriptor")?;
+            termios::update_termios(fd, |termios| {
+                if let Some(echo) = command.echo {
+                    termios::set_echo(termios, echo);
+                }
+                if let Some(onlcr) = command.onlcr {
+                    termios::set_onlcr(termios, onlcr);
+                }
+            })?;
+        }
+
+        let mut cmd = match command.shell {
+            None | Some(Shell::Enabled(false)) => CommandBuilder::new(command.cmd),
+            Some(shell) => {
+                let (default_shell, flag) = if cfg!(windows) {
+                    ("cmd.exe", "/C")
                 } else {
-                    unreachable!()
+                    ("/bin/sh", "-c")
+                };
+                let mut cmd = CommandBuilder::new(match &shell {
+                    Shell::Path(path) => path.as_str(),
+                    Shell::Enabled(_) => default_shell,
+                });
+                cmd.args([flag, &command.cmd]);
+                cmd
+            }
+        };
+        // the environment is built in a fixed order, each step overrides the previous ones:
+        // 1. the base: all the variables of the current process as CommandBuilder copies them, empty with clear_env
+        // 2. path replaces PATH, a default PATH is used if the current process doesn't have one
+        // 3. the TERM, LANG and COLORTERM defaults, only if they aren't inherited
+        // 4. COLUMNS and LINES of the initial size, with the defaults
+        // 5. env, in order
+        // 6. unset_env removals
+        let clear_env = command.clear_env.unwrap_or(false);
+        let default_env = !clear_env && command.default_env.unwrap_or(true);
+        if clear_env {
+            cmd.env_clear();
+        }
+        if let Some(path) = &command.path {
+            cmd.env("PATH", path);
+        } else if !clear_env && cmd.get_env("PATH").is_none() {
+            // https://github.com/wez/wezterm/issues/4205
+            // the current process might run without PATH, for example in a sandbox
+            cmd.env("PATH", DEFAULT_PATH);
+        }
+        if default_env {
+            let lang = if cfg!(target_os = "macos") {
+                Some("en_US.UTF-8")
+            } else if cfg!(unix) {
+                Some("C.UTF-8")
+            } else {
+                None
+            };
+            let truecolor = command.truecolor.unwrap_or(false).then_some("truecolor");
+            for (key, value) in [
+                ("TERM", Some("xterm-256color")),
+                ("LANG", lang),
+                ("COLORTERM", truecolor),
+            ] {
+                // the inherited values are kept, the ones in env are applied after
+                if let Some(value) = value.filter(|_| cmd.get_env(key).is_none()) {
+                    cmd.env(key, value);
                 }
-            })
-            .collect::<Vec<_>>()
-            .join("");
+            }
+            // the size of the terminal of the current process is wrong for the pty
+            let size = pair.master.get_size()?;
+            cmd.env("COLUMNS", size.cols.to_string());
+            cmd.env("LINES", size.rows.to_string());
+        }
+        for env in command.env {
+            cmd.env(env.0, env.1);
+        }
+        for key in command.unset_env.unwrap_or_default() {
+            cmd.env_remove(key);
+        }
+        cmd.args(&command.args);
+        cmd.cwd(&cwd);
+        // portable_pty applies it in the child before exec
+        #[cfg(unix)]
+        cmd.umask(command.umask.map(|umask| umask as libc::mode_t));
+        // these run the command through /bin/sh or nice, which report a missing command with the exit code 127
+        let wrapped = command.separate_stderr.unwrap_or(false)
+            || command.detach.unwrap_or(false)
+            || command.start_stopped.unwrap_or(false)
+            || (cfg!(unix) && command.nice.is_some());
+        if wrapped {
+            let program = cmd
+                .get_argv()
+                .first()
+                .map(|program| program.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if utils::find_program(&program, cmd.get_env("PATH"), &cwd).is_none() {
+                return Err(PtyError::new(
+                    ErrorKind::NotFound,
+                    format!("command not found: {program}"),
+                )
+                .into());
+            }
+        }
+        #[cfg(unix)]
+        let stderr_fifo = if command.separate_stderr.unwrap_or(false) {
+            let (fifo, reader) = stderr::StderrFifo::create()?;
+            fifo.wrap_argv(cmd.get_argv_mut());
+            Some((fifo, reader))
+        } else {
+            None
+        };
+        // separate_stderr is rejected by validate on windows
+        #[cfg(windows)]
+        let stderr_fifo: Option<((), std::fs::File)> = None;
+        let detach = command.detach.unwrap_or(false);
+        if detach {
+            #[cfg(unix)]
+            {
+                // the shell starts the command in the background and exits, the command is reparented to init
+                // it ignores the SIGHUP sent when the pty is closed and doesn't use the pty for its stdio
+                let argv = cmd.get_argv_mut();
+                let command = std::mem::take(argv);
+                argv.extend([
+                    "/bin/sh".into(),
+                    "-c".into(),
+                    r#"trap "" HUP; "$@" </dev/null >/dev/null 2>&1 & echo $!"#.into(),
+                    "sh".into(),
+                ]);
+                argv.extend(command);
+            }
+            #[cfg(windows)]
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "detach is not supported on windows",
+            )
+            .into());
+        }
+        if command.start_stopped.unwrap_or(false) {
+            #[cfg(unix)]
+            {
+                // portable_pty 0.8 has no pre exec hook to stop the child itself, so a shell stops itself and
+                // execs the command once it's continued. The pid stays the same, but until then the process
+                // is /bin/sh and not the command
+                let argv = cmd.get_argv_mut();
+                let command = std::mem::take(argv);
+                argv.extend([
+                    "/bin/sh".into(),
+                    "-c".into(),
+                    r#"kill -STOP $$; exec "$@""#.into(),
+                    "sh".into(),
+                ]);
+                argv.extend(command);
+            }
+            #[cfg(windows)]
+            return Err(PtyError::new(
+                ErrorKind::Unsupported,
+                "start_stopped is not supported on windows",
+            )
+            .into());
+        }
+        // portable_pty has no hook to run in the child before exec, so nice starts the command with the
+        // value already set, the wrapper shells above are started by it too
+        #[cfg(unix)]
+        if let Some(nice) = command.nice {
+            let program = utils::find_program("nice", Some(DEFAULT_PATH.as_ref()), &cwd)
+                .ok_or_else(|| {
+                    PtyError::new(
+                        ErrorKind::NotFound,
+                        format!("nice wasn't found in {DEFAULT_PATH}"),
+                    )
+                })?;
+            // nice adds to the value of the current process
+            let increment = nice - utils::priority();
+            cmd.get_argv_mut().splice(
+                0..0,
+                [program.into(), "-n".into(), increment.to_string().into()],
+            );
+        }
+
+        let end_drain = Duration::from_millis(command.end_drain_ms.unwrap_or(100));
+        // bounded so the reader thread blocks when the output isn't consumed, instead of growing the memory
+        // with max_buffered_bytes the memory is bounded by dropping the old output instead
+        let (tx_read, rx_read) = match command.max_buffered_bytes {
+            Some(_) => unbounded(),
+            None => bounded(command.read_channel_capacity.unwrap_or(1024).max(1)),
+        };
+        let ready = Arc::new(Ready::default());
+        let tx_read = ReadSender {
+            tx: tx_read,
+            ready: ready.clone(),
+        };
+
+        // for the command not found error
+        let program = cmd
+            .get_argv()
+            .first()
+            .map(|program| program.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut child = match pair.slave.spawn_command(cmd) {
+            Ok(child) => child,
+            Err(err) => {
+                // the error depends on the platform and on where the lookup failed, so normalize it
+                let not_found = err.chain().any(|err| {
+                    err.downcast_ref::<std::io::Error>()
+                        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+                }) || err.to_string().contains("doesn't exist on the filesystem");
+                if not_found {
+                    return Err(PtyError::new(
+                        ErrorKind::NotFound,
+                        format!("command not found: {program}"),
+                    )
+                    .into());
+                }
+                return Err(err.into());
+            }
+        };
+        // the process has its own copy, the reader gets EOF once it's closed
+        #[cfg(unix)]
+        drop(pair.slave);
+        let ck = child.clone_killer();
+        let pid = child.process_id();
+        // there is no equivalent of nice on windows, so the priority class is set right after the spawn
+        #[cfg(windows)]
+        if let Some(nice) = command.nice {
+            let set = pid
+                .ok_or_else(|| "failed to get the pid of the process".into())
+                .and_then(|pid| utils::set_priority(pid, nice));
+            if let Err(err) = set {
+                let _ = child.kill();
+                let _ = child.wait();
+                // keep the io error so a missing privilege is reported as PermissionDenied
+                return Err(err);
+            }
+        }
+        #[cfg(windows)]
+        let job = pid.and_then(|pid| match job::Job::new(pid) {
+            Ok(job) => Some(job),
+            Err(err) => {
+                log(
+                    LogLevel::Warn,
+                    &format!("failed to create a job for the process: {err}"),
+                );
+                None
+            }
+        });
+        // name the threads so they can be identified when debugging
+        let thread_name = |name: &str| match pid {
+            Some(pid) => format!("pty-{name}-{pid}"),
+            None => format!("pty-{name}"),
+        };
+
+        // If we do a pty.read after the process exit, read will hang
+        // Thats why we spawn another thread to wait for the child
+        // and signal its exit
+        let tx_read_c = tx_read.clone();
+        // the writer thread reports its errors through the read channel
+        let tx_read_w = tx_read.clone();
+        let exit = Arc::new(Exit {
+            pid,
+            ..Default::default()
+        });
+        let exit_c = exit.clone();
+        // when the process last wrote to the pty or the stderr, used by the idle timeout
+        let last_output = Arc::new(Mutex::new(Instant::now()));
+        let (stderr_fifo, stderr_reader) = stderr_fifo.unzip();
+        // never sent, the reader thread drops it after sending End
+        let (tx_eof, rx_eof) = bounded::<()>(0);
+        let exit_r = exit.clone();
+        std::thread::Builder::new()
+            .name(thread_name("waiter"))
+            .spawn(move || {
+                // portable_pty's ExitStatus doesn't have the signal number, so get it before reaping the child
+                #[cfg(unix)]
+                let signal = pid.and_then(utils::wait_exit_signal);
+                #[cfg(windows)]
+                let signal = None;
+                let status = catch_panic("waiter", &tx_read_c, || child.wait())
+                    .map_err(|err| err.message)
+                    .and_then(|status| status.map_err(|err| err.to_string()));
+                exit_c.set(status, signal);
+                // the stderr ends once the process and its children close the fifo
+                drop(stderr_fifo);
+                // the reader thread sends End once it reaches EOF, so all the output is sent before it
+                // this is only a fallback for children that keep the pty open, and windows where
+                // the slave is kept alive
+                if let Err(RecvTimeoutError::Timeout) = rx_eof.recv_timeout(end_drain) {
+                    exit_c.send_end(&tx_read_c);
+                }
+            })?;
+
+        if let Some(timeout) = command.timeout_ms.map(Duration::from_millis) {
+            let mut ck = ck.clone_killer();
+            let exit_c = exit.clone();
+            std::thread::Builder::new()
+                .name(thread_name("timeout"))
+                .spawn(move || {
+                    if exit_c.wait(Some(timeout)) {
+                        return;
+                    }
+                    exit_c.kill(
+                        &mut *ck,
+                        PtyError::new(
+                            ErrorKind::TimedOut,
+                            format!("the process timed out after {}ms", timeout.as_millis()),
+                        ),
+                    );
+                })?;
+        }
+        if let Some(idle_timeout) = command.idle_timeout_ms.map(Duration::from_millis) {
+            let mut ck = ck.clone_killer();
+            let exit_c = exit.clone();
+            let last_output_c = last_output.clone();
+            std::thread::Builder::new()
+                .name(thread_name("idle-timeout"))
+                .spawn(move || loop {
+                    let remaining = idle_timeout.saturating_sub(last_output_c.lock().elapsed());
+                    if remaining.is_zero() {
+                        exit_c.kill(
+                            &mut *ck,
+                            PtyError::new(
+                                ErrorKind::TimedOut,
+                                format!(
+                                    "the process didn't output anything for {}ms",
+                                    idle_timeout.as_millis()
+                                ),
+                            ),
+                        );
+                        return;
+                    }
+                    if exit_c.wait(Some(remaining)) {
+                        return;
+                    }
+                })?;
+        }
+
+        // Read the output in another thread.
+        // This is important because it is easy to encounter a situation
+        // where read/write buffers fill and block either your process
+        // or the spawned process.
+        let mut reader = pair.master.try_clone_reader()?;
+        let decode = command.decode.unwrap_or(Decode::Strict);
+        let pending_bytes = Arc::new(AtomicUsize::new(0));
+        let pending_bytes_c = pending_bytes.clone();
+        let dropped_bytes = Arc::new(AtomicUsize::new(0));
+        let held = Held::default();
+        let ring = command.max_buffered_bytes.map(|max_bytes| RingBuffer {
+            rx_read: rx_read.clone(),
+            max_bytes,
+            dropped_bytes: dropped_bytes.clone(),
+            held: held.clone(),
+        });
+        let stats = Arc::new(Stats::default());
+        let stats_c = stats.clone();
+        let last_output_c = last_output.clone();
+        let transcript = command
+            .record
+            .unwrap_or(false)
+            .then(|| Arc::new(Transcript::new(command.record_max_bytes)));
+        let transcript_c = transcript.clone();
+        // ignored on windows
+        #[cfg(unix)]
+        let coalesce = match command.coalesce_ms {
+            Some(ms) => {
+                let fd = pair
+                    .master
+                    .as_raw_fd()
+                    .ok_or("failed to get the pty file descriptor")?;
+                Some(Coalesce {
+                    window: Duration::from_millis(ms),
+                    fd: unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?,
+                })
+            }
+            None => None,
+        };
+        #[cfg(windows)]
+        let coalesce = None;
+        std::thread::Builder::new()
+            .name(thread_name("reader"))
+            .spawn(move || {
+                // dropped when the thread exits, which wakes up the waiter
+                let _tx_eof = tx_eof;
+                // the error is returned by the next read, and End once the process exits
+                let _ = catch_panic("reader", &tx_read, || {
+                    read_loop(
+                        &mut reader,
+                        &tx_read,
+                        decode,
+                        &pending_bytes_c,
+                        ring.as_ref(),
+                        coalesce.as_ref(),
+                        &mut |data| {
+                            *last_output_c.lock() = Instant::now();
+                            stats_c
+                                .read_bytes
+                                .fetch_add(data.len() as u64, Ordering::Relaxed);
+                            if let Some(transcript) = &transcript_c {
+                                transcript.push(data);
+                            }
+                            if cast.as_mut().is_some_and(|cast| !cast.write_output(data)) {
+                                cast = None;
+                            }
+                        },
+                    )
+                });
+                // NOTE: wait for the status, so it is available as soon as read reports the exit
+                exit_r.wait(None);
+                exit_r.send_end(&tx_read);
+            })?;
+
+        let stderr = match stderr_reader {
+            Some(mut reader) => {
+                let (tx_stderr, rx_stderr) =
+                    bounded(command.read_channel_capacity.unwrap_or(1024).max(1));
+                let ready = Arc::new(Ready::default());
+                let tx_stderr = ReadSender {
+                    tx: tx_stderr,
+                    ready: ready.clone(),
+                };
+                let pending_bytes = Arc::new(AtomicUsize::new(0));
+                let pending_bytes_c = pending_bytes.clone();
+                let last_output_c = last_output.clone();
+                let stats_c = stats.clone();
+                std::thread::Builder::new()
+                    .name(thread_name("stderr"))
+                    .spawn(move || {
+                        let _ = catch_panic("stderr", &tx_stderr, || {
+                            read_loop(
+                                &mut reader,
+                                &tx_stderr,
+                                decode,
+                                &pending_bytes_c,
+                                None,
+                                None,
+                                &mut |data| {
+                                    *last_output_c.lock() = Instant::now();
+                                    stats_c
+                                        .read_bytes
+                                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                                },
+                            )
+                        });
+                        // unlike the pty, the end of the stderr is known from the pipe
+                        let _ = tx_stderr.send((Instant::now(), Message::End));
+                    })?;
+                Some(PtyReader::new(
+                    rx_stderr,
+                    pending_bytes,
+                    Arc::new(AtomicUsize::new(0)),
+                    ready,
+                    Held::default(),
+                    started,
+                ))
+            }
+            None => None,
+        };
+
+        let mut writer = pair.master.take_writer()?;
+        // the writer is kept after closing stdin so it can be reopened, this fd is used to read the EOF character
+        #[cfg(unix)]
+        let eof_fd = {
+            let fd = pair
+                .master
+                .as_raw_fd()
+                .ok_or("failed to get the pty file descriptor")?;
+            unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?
+        };
+        let (tx_write, rx_write): (Sender<WriteMessage>, _) = match command.write_channel_capacity {
+            Some(capacity) => bounded(capacity.max(1)),
+            None => unbounded(),
+        };
+        let writer_closed = Arc::new(Mutex::new(None));
+        let writer_closed_c = writer_closed.clone();
+        let stats_c = stats.clone();
+        std::thread::Builder::new()
+            .name(thread_name("writer"))
+            .spawn(move || {
+                let result = catch_panic("writer", &tx_read_w, || {
+                    while let Ok(msg) = rx_write.recv() {
+                        match msg {
+                            WriteMessage::Data(buf) => {
+                                if let Err(err) =
+                                    writer.write_all(&buf).and_then(|_| writer.flush())
+                                {
+                                    let err = PtyError::new(
+                                        ErrorKind::Io,
+                                        format!("failed to write data: {err}"),
+                                    );
+                                    *writer_closed_c.lock() = Some(err.clone());
+                                    log(LogLevel::Error, &err.message);
+                                    tx_read_w.send((Instant::now(), Message::Error(err))).ok();
+                                    break;
+                                }
+                                stats_c
+                                    .written_bytes
+                                    .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                            }
+                            WriteMessage::File(mut file, path) => {
+                                let mut buf = [0; 8192];
+                                loop {
+                                    let n = match file.read(&mut buf) {
+                                        Ok(0) => break,
+                                        Ok(n) => n,
+                                        Err(err)
+                                            if err.kind() == std::io::ErrorKind::Interrupted =>
+                                        {
+                                            continue
+                                        }
+                                        // the writer still works, only this write is stopped
+                                        Err(err) => {
+                                            let err = PtyError::new(
+                                                ErrorKind::Io,
+                                                format!("failed to read {path}: {err}"),
+                                            );
+                                            log(LogLevel::Error, &err.message);
+                                            tx_read_w
+                                                .send((Instant::now(), Message::Error(err)))
+                                                .ok();
+                                            break;
+                                        }
+                                    };
+                                    if let Err(err) =
+                                        writer.write_all(&buf[..n]).and_then(|_| writer.flush())
+                                    {
+                                        let err = PtyError::new(
+                                            ErrorKind::Io,
+                                            format!("failed to write data: {err}"),
+                                        );
+                                        *writer_closed_c.lock() = Some(err.clone());
+                                        log(LogLevel::Error, &err.message);
+                                        tx_read_w.send((Instant::now(), Message::Error(err))).ok();
+                                        return;
+                                    }
+                                    stats_c.written_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                                }
+                            }
+                            WriteMessage::Flush(ack) => {
+                                // all the previous writes are done at this point since the channel is ordered
+                                let _ = ack.send(writer.flush());
+                            }
+                            // send EOF like portable_pty does when the writer is dropped, it's only seen
+                            // at the start of a line so a newline is sent first
+                            #[cfg(unix)]
+                            WriteMessage::Close => {
+                                let eof =
+                                    termios::eof_char(std::os::fd::AsRawFd::as_raw_fd(&eof_fd));
+                                let result = match eof {
+                                    Ok(0) => Ok(()),
+                                    Ok(eof) => writer
+                                        .write_all(&[b'\n', eof])
+                                        .and_then(|_| writer.flush())
+                                        .map_err(Into::into),
+                                    Err(err) => Err(err),
+                                };
+                                if let Err(err) = result {
+                                    let err = PtyError::new(
+                                        ErrorKind::Io,
+                                        format!("failed to close stdin: {err}"),
+                                    );
+                                    *writer_closed_c.lock() = Some(err.clone());
+                                    log(LogLevel::Error, &err.message);
+                                    tx_read_w.send((Instant::now(), Message::Error(err))).ok();
+                                    break;
+                                }
+                            }
+                            // dropping the writer closes the input pipe of conpty, it can't be reopened
+                            #[cfg(windows)]
+                            WriteMessage::Close => break,
+                        }
+                    }
+                });
+                // the next writes fail instead of being queued forever
+                if let Err(err) = result {
+                    *writer_closed_c.lock() = Some(err);
+                }
+            })?;
+
+        Ok(Self {
+            reader: PtyReader::new(rx_read, pending_bytes, dropped_bytes, ready, held, started),
+            stderr,
+            tx_write,
+            writer_closed,
+            #[cfg(windows)]
+            _slave: pair.slave,
+            master: pair.master,
+            ck: Mutex::new(ck),
+            pid,
+            #[cfg(windows)]
+            job,
+            close_signal: command.close_signal,
+            detached: detach,
+            line_ending: command.line_ending.unwrap_or(LineEnding::Lf),
+            write_chunk_size: command.write_chunk_size.unwrap_or(4096).max(1),
+            transcript,
+            stats,
+            last_regex: Mutex::new(None),
+            exit,
+        })
+    }
+
+    fn clone_reader(&self) -> PtyReader {
+        self.reader.clone()
+    }
+
+    fn read(&self) -> Result<Message> {
+        self.reader.read()
+    }
+
+    fn read_timeout(&self, timeout: Duration) -> Result<Option<Message>> {
+        self.reader.read_timeout(timeout)
+    }
+
+    /// Wakes up a read of the output or the stderr that is blocked, for example on shutdown
+    fn interrupt_read(&self) {
+        self.reader.interrupt();
+        if let Some(stderr) = &self.stderr {
+            stderr.interrupt();
+        }
+    }
+
+    fn read_stderr(&self) -> Result<Message> {
+        match &self.stderr {
+            Some(stderr) => stderr.read(),
+            None => Err(PtyError::new(
+                ErrorKind::InvalidInput,
+                "stderr is not separated, set separate_stderr when creating the pty",
+            )
+            .into()),
+        }
+    }
+
+    fn read_until(&self, needle: &str, timeout: Duration) -> Result<(String, ReadUntil)> {
+        self.reader.read_until(needle, timeout)
+    }
+
+    fn read_until_regex(&self, pattern: &str, timeout: Duration) -> Result<(String, ReadUntil)> {
+        let regex = {
+            let mut last_regex = self.last_regex.lock();
+            match &*last_regex {
+                Some(regex) if regex.as_str() == pattern => regex.clone(),
+                _ => {
+                    let regex = Regex::new(pattern)
+                        .map_err(|err| PtyError::new(ErrorKind::InvalidInput, err.to_string()))?;
+                    *last_regex = Some(regex.clone());
+                    regex
+                }
+            }
+        };
+        self.reader.read_until_regex(&regex, timeout)
+    }
+
+    /// Writes the input then reads until `until` appears in the output, for request/response protocols
+    ///
+    /// NOTE: the output that wasn't read before the write is part of the result
+    fn exchange(
+        &self,
+        input: Vec<u8>,
+        until: &str,
+        timeout: Duration,
+    ) -> Result<(String, ReadUntil)> {
+        self.write_bytes(input)?;
+        self.reader.read_until(until, timeout)
+    }
+
+    fn read_into(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        self.reader.read_into(buf)
+    }
+
+    /// Reads until the process exits, a None timeout waits forever
+    ///
+    /// Returns all the output and the exit code, the exit code is None on timeout
+    fn collect(&self, timeout: Option<Duration>) -> Result<(String, Option<u32>)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut output = String::new();
+        loop {
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => Duration::MAX,
+            };
+            match self.read_timeout(remaining)? {
+                Some(Message::Data(data)) => output.push_str(&data),
+                // the exit status is set before End is sent
+                Some(Message::End) => return Ok((output, self.exit_code()?)),
+                Some(Message::Error(err)) => return Err(err.into()),
+                None => return Ok((output, None)),
+            }
+        }
+    }
+
+    /// Returns all the buffered data without waiting, and whether the process exited after it
+    fn drain(&self) -> Result<(String, bool)> {
+        match self.reader.read()? {
+            Message::Data(data) => Ok((data, self.reader.ended())),
+            Message::End => Ok((String::new(), true)),
+            Message::Error(err) => Err(err.into()),
+        }
+    }
+
+    /// Same as read, also returns when the data was received relative to the pty creation
+    fn read_timed(&self) -> Result<(Message, Duration)> {
+        let msg = self.reader.read()?;
+        let at = self
+            .reader
+            .last_data_at
+            .lock()
+            .map_or(Duration::ZERO, |at| at - self.reader.started);
+        Ok((msg, at))
+    }
+
+    fn pending_bytes(&self) -> usize {
+        self.reader.pending_bytes.load(Ordering::Relaxed)
+    }
+
+    fn write(&self, data: String) -> Result<()> {
+        self.write_bytes(data.into_bytes())
+    }
+
+    /// Writes the data followed by the line ending
+    fn write_line(&self, data: String) -> Result<()> {
+        let line_ending = match self.line_ending {
+            LineEnding::Lf => "\n",
+            LineEnding::Cr => "\r",
+        };
+        self.write(data + line_ending)
+    }
+
+    /// Writes the data wrapped in the bracketed paste sequences, so the program handles it as pasted text
+    /// instead of typed keys
+    fn write_paste(&self, data: &[u8]) -> Result<()> {
+        const START: &[u8] = b"\x1b[200~";
+        const END: &[u8] = b"\x1b[201~";
+        // the end sequence is removed from the data so the paste can't end early
+        let mut data = data.to_vec();
+        while let Some(i) = data.windows(END.len()).position(|window| window == END) {
+            data.drain(i..i + END.len());
+        }
+        self.write_bytes([START, &data, END].concat())
+    }
+
+    /// Streams the file to the process from the writer thread, after the queued writes
+    ///
+    /// The file is opened now so a missing file is reported right away, the read errors are
+    /// reported by the next read
+    fn write_file(&self, path: &str) -> Result<()> {
+        if let Some(err) = self.writer_closed.lock().clone() {
+            return Err(err.into());
+        }
+        let file = std::fs::File::open(path).map_err(|err| {
+            let kind = match err.kind() {
+                std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                _ => ErrorKind::Io,
+            };
+            PtyError::new(kind, format!("failed to open {path}: {err}"))
+        })?;
+        self.tx_write
+            .send(WriteMessage::File(file, path.to_owned()))
+            .map_err(|_| self.writer_closed_error().into())
+    }
+
+    fn write_bytes(&self, data: Vec<u8>) -> Result<()> {
+        if let Some(err) = self.writer_closed.lock().clone() {
+            return Err(err.into());
+        }
+        if data.len() <= self.write_chunk_size {
+            return self
+                .tx_write
+                .send(WriteMessage::Data(data))
+                .map_err(|_| self.writer_closed_error().into());
+        }
+        // the writer thread flushes after each chunk, so the process gets the start of a big write
+        // while the rest is queued, and pending_writes shows the progress
+        for chunk in data.chunks(self.write_chunk_size) {
+            self.tx_write
+                .send(WriteMessage::Data(chunk.to_vec()))
+                .map_err(|_| self.writer_closed_error())?;
+        }
+        Ok(())
+    }
+
+    fn writer_closed_error(&self) -> PtyError {
+        self.writer_closed
+            .lock()
+            .clone()
+            .unwrap_or_else(|| PtyError::new(ErrorKind::Disconnected, "pty writer closed"))
+    }
+
+    /// Writes the control character of `ch`, for example `C` writes Ctrl-C (0x03)
+    fn send_control(&self, ch: u32) -> Result<()> {
+        self.write_bytes(vec![control_byte(ch)?])
+    }
+
+    /// Blocks until all the queued writes are written to the pty
+    fn flush(&self) -> Result<()> {
+        self.flush_timeout(None).map(|_| ())
+    }
+
+    /// Same as flush but gives up after the timeout, a None timeout waits forever
+    ///
+    /// Returns false on timeout
+    fn flush_timeout(&self, timeout: Option<Duration>) -> Result<bool> {
+        let (ack_tx, ack_rx) = bounded(1);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        // with write_channel_capacity the channel can be full, the timeout includes waiting for space
+        match deadline {
+            Some(deadline) => match self
+                .tx_write
+                .send_deadline(WriteMessage::Flush(ack_tx), deadline)
+            {
+                Ok(()) => {}
+                Err(SendTimeoutError::Timeout(_)) => return Ok(false),
+                Err(SendTimeoutError::Disconnected(_)) => {
+                    return Err(self.writer_closed_error().into())
+                }
+            },
+            None => self
+                .tx_write
+                .send(WriteMessage::Flush(ack_tx))
+                .map_err(|_| self.writer_closed_error())?,
+        }
+        let ack = match deadline {
+            Some(deadline) => match ack_rx.recv_deadline(deadline) {
+                Ok(ack) => ack,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(self.writer_closed_error().into())
+                }
+            },
+            None => ack_rx.recv().map_err(|_| self.writer_closed_error())?,
+        };
+        ack?;
+        Ok(true)
+    }
+
+    /// Same as write_bytes but doesn't wait for space when the write channel is full
+    ///
+    /// Returns false if the channel is full, nothing is queued then
+    fn try_write(&self, data: Vec<u8>) -> Result<bool> {
+        if let Some(err) = self.writer_closed.lock().clone() {
+            return Err(err.into());
+        }
+        // not split in chunks, so the data is either queued or not
+        match self.tx_write.try_send(WriteMessage::Data(data)) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Disconnected(_)) => Err(self.writer_closed_error().into()),
+        }
+    }
+
+    /// Writes the data and waits until it's written to the pty
+    ///
+    /// Returns false on timeout, the data stays queued and is written when the process reads its input
+    fn write_timeout(&self, data: Vec<u8>, timeout: Duration) -> Result<bool> {
+        self.write_bytes(data)?;
+        self.flush_timeout(Some(timeout))
+    }
 
-        Ok(Message::Data(msg))
+    fn close_stdin(&self) -> Result<()> {
+        let mut writer_closed = self.writer_closed.lock();
+        if let Some(err) = writer_closed.clone() {
+            return Err(err.into());
+        }
+        if self.tx_write.send(WriteMessage::Close).is_err() {
+            return Err(PtyError::new(ErrorKind::Disconnected, "pty writer closed").into());
+        }
+        // set now instead of by the writer thread, so the writes after reopen_stdin are not rejected
+        *writer_closed = Some(stdin_closed_error());
+        Ok(())
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct Command {
-    cmd: String,
-    args: Vec<String>,
-    env: Vec<(String, String)>,
-    cwd: Option<String>,
-}
+    /// Allows writing again after close_stdin, the process reads the new input after the EOF
+    #[cfg(unix)]
+    fn reopen_stdin(&self) -> Result<()> {
+        let mut writer_closed = self.writer_closed.lock();
+        match writer_closed.clone() {
+            Some(err) if err == stdin_closed_error() => {
+                *writer_closed = None;
+                Ok(())
+            }
+            // the writer thread stopped
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
 
-#[derive(PartialEq, Eq, Debug)]
-enum Message {
-    Data(String),
-    End,
-}
+    #[cfg(windows)]
+    fn reopen_stdin(&self) -> Result<()> {
+        Err(PtyError::new(ErrorKind::Unsupported, "stdin can't be reopened on windows").into())
+    }
 
-impl Pty {
-    fn create(command: Command) -> Result<Self> {
-        // Use the native pty implementation for the system
-        let pty_system = native_pty_system();
+    fn resize(&self, size: PtySize) -> Result<()> {
+        self.master.resize(size).map_err(Into::into)
+    }
 
-        // Create a new pty
-        let pair = pty_system.openpty(PtySize {
-            rows: 24,
-            cols: 80,
-            // Not all systems support pixel_width, pixel_height,
-            // but it is good practice to set it to something
-            // that matches the size of the selected font.  That
-            // is more complex than can be shown here in this
-            // brief example though!
-            pixel_width: 0,
-            pixel_height: 0,
-        })?;
+    fn get_size(&self) -> Result<PtySize> {
+        self.master.get_size().map_err(Into::into)
+    }
 
-        let mut cmd = CommandBuilder::new(command.cmd);
-        // https://github.com/wez/wezterm/issues/4205
-        cmd.env("PATH", std::env::var("PATH")?);
-        cmd.args(&command.args);
-        match command.cwd {
-            Some(cwd) => cmd.cwd(cwd),
-            None => cmd.cwd(std::env::current_dir()?),
+    /// Resizes the pty and reads the size back to check that it was applied
+    ///
+    /// On unix SIGWINCH is sent again to the foreground process group, for programs that missed
+    /// the one sent by the kernel
+    fn resize_verified(&self, size: PtySize) -> Result<()> {
+        self.resize(size)?;
+        let applied = self.get_size()?;
+        // the pixel size isn't supported everywhere
+        if (applied.rows, applied.cols) != (size.rows, size.cols) {
+            return Err(PtyError::new(
+                ErrorKind::Io,
+                format!(
+                    "the pty size is {}x{} after resizing it to {}x{}",
+                    applied.rows, applied.cols, size.rows, size.cols
+                ),
+            )
+            .into());
         }
-        for env in command.env {
-            cmd.env(env.0, env.1);
+        #[cfg(unix)]
+        if let Some(pgid) = self.master.process_group_leader() {
+            // the size is applied even if the process already exited
+            unsafe { libc::killpg(pgid, libc::SIGWINCH) };
         }
+        Ok(())
+    }
 
-        let (tx_read, rx_read) = unbounded();
+    #[cfg(unix)]
+    fn set_raw(&self, raw: bool) -> Result<()> {
+        let fd = self
+            .master
+            .as_raw_fd()
+            .ok_or("failed to get the pty file descriptor")?;
+        // the master and the slave share the same termios
+        termios::update_termios(fd, |termios| termios::set_raw(termios, raw))
+    }
 
-        let mut child = pair.slave.spawn_command(cmd)?;
-        let ck = child.clone_killer();
+    #[cfg(windows)]
+    fn set_raw(&self, _raw: bool) -> Result<()> {
+        Err(PtyError::new(
+            ErrorKind::Unsupported,
+            "raw mode is not supported on windows",
+        )
+        .into())
+    }
 
-        // If we do a pty.read after the process exit, read will hang
-        // Thats why we spawn another thread to wait for the child
-        // and signal its exit
-        let tx_read_c = tx_read.clone();
-        std::thread::spawn(move || {
-            let _ = child.wait();
-            let _ = tx_read_c.send(Message::End);
-        });
+    #[cfg(unix)]
+    fn tty_name(&self) -> Result<String> {
+        let fd = self
+            .master
+            .as_raw_fd()
+            .ok_or("failed to get the pty file descriptor")?;
+        utils::ptsname(fd)
+    }
 
-        // Read the output in another thread.
-        // This is important because it is easy to encounter a situation
-        // where read/write buffers fill and block either your process
-        // or the spawned process.
-        let mut reader = pair.master.try_clone_reader()?;
-        std::thread::spawn(move || {
-            let mut buf = [0; 512];
-            loop {
-                let n = reader.read(&mut buf).expect("failed to read data");
-                if n == 0 {
-                    // the pty has already exited
-                    // so no need to send the end message?
-                    break;
-                };
-                tx_read
-                    .send(Message::Data(
-                        String::from_utf8(buf[0..n].to_vec()).expect("data is not valid utf8"),
-                    ))
-                    .ok(); // the sender closed (the program finished ?);
-            }
-        });
+    #[cfg(windows)]
+    fn tty_name(&self) -> Result<String> {
+        Err(PtyError::new(
+            ErrorKind::Unsupported,
+            "tty name is not supported on windows",
+        )
+        .into())
+    }
 
-        let mut writer = pair.master.take_writer()?;
-        let (tx_write, rx_write): (Sender<String>, _) = unbounded();
-        std::thread::spawn(move || {
-            while let Ok(buf) = rx_write.recv() {
-                writer
-                    .write_all(&buf.into_bytes())
-                    .expect("failed to write data");
-            }
-        });
+    /// Opens a new fd to the slave, the slave used by the process is closed after spawning
+    #[cfg(unix)]
+    fn slave_fd(&self) -> Result<std::os::fd::OwnedFd> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            // don't make it the controlling terminal of the current process
+            .custom_flags(libc::O_NOCTTY)
+            .open(self.tty_name()?)?;
+        Ok(file.into())
+    }
 
-        Ok(Self {
-            reader: PtyReader::new(rx_read),
-            tx_write,
-            _slave: pair.slave,
-            master: pair.master,
-            ck,
-        })
+    #[cfg(windows)]
+    fn slave_fd(&self) -> Result<i32> {
+        Err(PtyError::new(
+            ErrorKind::Unsupported,
+            "the slave fd is not supported on windows",
+        )
+        .into())
     }
 
-    #[allow(dead_code)]
-    fn clone_reader(&self) -> PtyReader {
-        self.reader.clone()
+    #[cfg(unix)]
+    fn signal(&self, signal: i32) -> Result<()> {
+        // send the signal to the foreground process group, like the terminal does for Ctrl-C
+        let pgid = self
+            .master
+            .process_group_leader()
+            .ok_or("failed to get the process group of the pty")?;
+        if unsafe { libc::killpg(pgid, signal) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
     }
 
-    fn read(&self) -> Result<Message> {
-        self.reader.read()
+    /// Sends the signal to the process group of the process, which includes its children
+    /// unless they moved to another group
+    #[cfg(unix)]
+    fn signal_group(&self, signal: i32) -> Result<()> {
+        // the process is a session leader, so its pid is also its process group id
+        let pid = self.pid.ok_or("failed to get the pid of the process")?;
+        if unsafe { libc::killpg(pid as libc::pid_t, signal) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
     }
 
-    fn write(&self, data: String) -> Result<()> {
-        Ok(self.tx_write.send(data)?)
+    #[cfg(windows)]
+    fn signal_group(&self, signal: i32) -> Result<()> {
+        self.signal(signal)
     }
 
-    fn resize(&self, size: PtySize) -> Result<()> {
-        self.master.resize(size).map_err(Into::into)
+    /// Sends the close signal if it's set, otherwise kills the process tree
+    fn kill_on_close(&self) -> Result<()> {
+        if self.detached {
+            // only the shell that started the command, it exits right away
+            if !self.exit.exited() {
+                self.ck.lock().kill()?;
+            }
+            return Ok(());
+        }
+        match self.close_signal {
+            Some(signal) => self.signal_group(signal),
+            None => self.kill_tree(),
+        }
     }
 
-    fn get_size(&self) -> Result<PtySize> {
-        self.master.get_size().map_err(Into::into)
+    /// Kills the process and its children
+    #[cfg(unix)]
+    fn kill_tree(&self) -> Result<()> {
+        // SIGKILL
+        if self.signal_group(9).is_err() {
+            // the group is gone once all its processes exited, the process might still need to be killed
+            self.ck.lock().kill()?;
+        }
+        Ok(())
+    }
+
+    /// Kills the process and its children
+    #[cfg(windows)]
+    fn kill_tree(&self) -> Result<()> {
+        match &self.job {
+            Some(job) => job.terminate(),
+            // killing doesn't work https://github.com/wez/wezterm/issues/5107
+            None => Err("the process isn't part of a job".into()),
+        }
     }
-}
 
-// note: need to be careful with names with no_mangle extern C
-// for example extern C write, will cause weird bugs
+    #[cfg(windows)]
+    fn signal(&self, signal: i32) -> Result<()> {
+        // windows doesn't have signals, so emulate the common ones
+        match signal {
+            // SIGINT
+            2 => self.write("\x03".into()),
+            // SIGKILL, SIGTERM
+            9 | 15 => self.ck.lock().kill().map_err(Into::into),
+            _ => Err(format!("signal {signal} is not supported on windows").into()),
+        }
+    }
 
-/// # Safety
-/// - Requires a valid pointer to a Command
-/// - Requires a valid pointer to a buffer of size 8
-/// to write the result to
-///
-/// Returns -1 on error
-#[no_mangle]
-// can't use new since its a reserved keyword in javascript
-pub unsafe extern "C" fn pty_create(command: *mut c_char, result: *mut usize) -> i8 {
-    let pty = (|| -> Result<Box<Pty>> {
-        let command = cstr_to_type::<Command>(command)?;
-        let pty = Pty::create(command)?;
-        Ok(Box::new(pty))
-    })();
-    match pty {
-        Ok(pty) => {
-            *result = Box::into_raw(pty) as usize;
-            0
-        }
-        Err(err) => {
-            *result = boxed_error_to_cstring(err).into_raw() as _;
-            -1
-        }
-    }
-}
-
-/// # Safety
-/// - Requires a valid pointer to a Pty
-/// - Requires a valid pointer to a buffer of size 8
-/// to write the result to
-///
-/// Returns -1 on error
-/// Returns 99 on process exit
-#[no_mangle]
-pub unsafe extern "C" fn pty_read(this: *mut Pty, result: *mut usize) -> i8 {
-    enum R {
-        Data(CString),
-        End,
-    }
-    match (|| -> Result<R> {
-        let this = unsafe { &*this };
-        // TODO: add a test for null byte inside str from read
-        let msg = this.read()?;
-        match msg {
-            Message::Data(data) => Ok(R::Data(CString::new(data.replace('\0', ""))?)),
-            Message::End => Ok(R::End),
-        }
-    })() {
-        Ok(data) => match data {
-            R::Data(str) => {
-                *result = str.into_raw() as _;
-                0
-            }
-            R::End => 99,
-        },
-        Err(err) => {
-            *result = boxed_error_to_cstring(err).into_raw() as _;
-            -1
-        }
-    }
-}
-
-/// # Safety
-/// - Requires a valid pointer to a Pty
-/// - Requires a valid pointer to data encoded as Cstring
-/// - Requires a valid pointer to a buffer of size 8
-/// to write the result to
-///
-/// Returns -1 on error
-#[no_mangle]
-pub unsafe extern "C" fn pty_write(this: *mut Pty, data: *mut c_char, result: *mut usize) -> i8 {
-    let this = unsafe { &*this };
-    let data = ManuallyDrop::new(CString::from_raw(data));
-    match (|| {
-        let data_str = data.to_str()?.to_owned(); // NOTE: can we send str in the channels ?
-        this.write(data_str)
-    })() {
-        Ok(()) => 0,
-        Err(err) => {
-            *result = boxed_error_to_cstring(err).into_raw() as _;
-            -1
-        }
-    }
-}
-
-/// # Safety
-/// - Requires a valid pointer to a Pty
-/// - Requires a valid pointer to a buffer of size 8
-/// to write the result to
-///
-/// Returns -1 on error
-#[no_mangle]
-pub unsafe extern "C" fn pty_get_size(this: *mut Pty, result: *mut usize) -> i8 {
-    let this = unsafe { &*this };
-    match (|| -> Result<CString> {
-        let size = this.get_size()?;
-        type_to_cstr(&size)
-    })() {
-        Ok(size) => {
-            *result = size.into_raw() as _;
-            0
-        }
-        Err(err) => {
-            *result = boxed_error_to_cstring(err).into_raw() as _;
-            -1
-        }
-    }
-}
-
-/// # Safety
-/// - Requires a valid pointer to a Pty
-/// - Requires a valid pointer to a PtySize encoded as CString
-/// - Requires a valid pointer to a buffer of size 8
-/// to write the error to
-///
-/// Returns -1 on error
-#[no_mangle]
-pub unsafe extern "C" fn pty_resize(this: *mut Pty, size: *mut c_char, result: *mut usize) -> i8 {
-    let this = unsafe { &*this };
-    match (|| -> Result<()> {
-        let size = cstr_to_type::<PtySize>(size)?;
-        this.resize(size)?;
+    /// Sends SIGTERM and kills the process if it didn't exit after the grace period
+    fn terminate(&self, grace: Duration) -> Result<()> {
+        if self.exit.exited() {
+            return Ok(());
+        }
+        // SIGTERM
+        if let Err(err) = self.signal(15) {
+            // the process might have exited in the meantime
+            if !self.exit.exited() {
+                return Err(err);
+            }
+        }
+        if !self.exit.wait(Some(grace)) {
+            self.ck.lock().kill()?;
+        }
         Ok(())
-    })() {
-        Ok(()) => 0,
-        Err(err) => {
-            *result = boxed_error_to_cstring(err).into_raw() as _;
-            -1
+    }
+
+    /// Returns false on timeout
+    fn wait(&self, timeout: Option<Duration>) -> Result<bool> {
+        if !self.exit.wait(timeout) {
+            return Ok(false);
         }
+        // surface the error if waiting on the child failed
+        self.exit_code().map(|_| true)
+    }
+
+    fn transcript(&self) -> Result<Vec<u8>> {
+        match &self.transcript {
+            Some(transcript) => Ok(transcript.data.lock().clone()),
+            None => Err(PtyError::new(
+                ErrorKind::InvalidInput,
+                "the output isn't recorded, set record when creating the pty",
+            )
+            .into()),
+        }
+    }
+
+    /// Returns None if the process is still running or wasn't terminated by a signal
+    fn exit_signal(&self) -> Option<i32> {
+        *self.exit.signal.lock()
     }
-}
 
-/// # Safety
-/// - Requires a valid pointer to a Pty
-#[no_mangle]
-pub unsafe extern "C" fn pty_close(this: *mut Pty) {
-    // NOTE: Dropping the pty doensn't work on windows and trigger random bugs https://github.com/sigmaSd/deno-pty-ffi/issues/3
-    if cfg!(windows) {
-        let _this = ManuallyDrop::new(Box::from_raw(this));
-        // killing doesn't work https://github.com/wez/wezterm/issues/5107
-        // let _ = this.ck.kill();
-    } else {
-        let mut this = Box::from_raw(this);
-        // NOTE: maybe propage the possible error
-        let _ = this.ck.kill();
+    /// Returns None if the process is still running
+    fn exit_code(&self) -> Result<Option<u32>> {
+        match &*self.exit.status.lock() {
+            Some(Ok(status)) => Ok(Some(status.exit_code())),
+            Some(Err(err)) => Err(err.clone().into()),
+            None => Ok(None),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::mpsc;
+    use std::{ffi::CString, sync::mpsc};
 
     use super::*;
+    use utils::{boxed_error_to_cstring, decode_length_prefixed};
+
+    fn test_reader(rx: Receiver<TimedMessage>) -> PtyReader {
+        PtyReader::new(
+            rx,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::default(),
+            Held::default(),
+            Instant::now(),
+        )
+    }
+
     #[test]
     fn it_works() {
         let mut threads = vec![];
@@ -362,7 +2308,7 @@ mod tests {
                     cmd: "deno".into(),
                     args: vec!["repl".into()],
                     env: vec![("NO_COLOR".into(), "1".into())],
-                    cwd: None,
+                    ..Default::default()
                 })
                 .unwrap();
 
@@ -383,7 +2329,7 @@ mod tests {
                                     break;
                                 }
                             }
-                            Message::End => break,
+                            Message::End | Message::Error(_) => break,
                         }
                     });
                     rx.recv().unwrap();
@@ -423,4 +2369,673 @@ mod tests {
         }
         threads.into_iter().for_each(|t| t.join().unwrap());
     }
+
+    #[test]
+    fn decode_split_utf8() {
+        let mut pending = "hé".as_bytes()[..2].to_vec();
+        assert_eq!(decode_utf8(&mut pending, Decode::Strict).unwrap(), "h");
+        assert_eq!(pending, [0xc3]);
+
+        pending.push(0xa9);
+        assert_eq!(decode_utf8(&mut pending, Decode::Strict).unwrap(), "é");
+        assert!(pending.is_empty());
+
+        let mut pending = vec![b'a', 0xff, b'b'];
+        assert!(decode_utf8(&mut pending, Decode::Strict).is_err());
+        assert_eq!(
+            decode_utf8(&mut pending, Decode::Lossy).unwrap(),
+            "a\u{fffd}b"
+        );
+
+        let mut pending = vec![b'a', 0xff, 0x00, b'\\', 0xc3, 0xa9, 0xc3];
+        assert_eq!(
+            decode_utf8(&mut pending, Decode::Hex).unwrap(),
+            "a\\xff\0\\é"
+        );
+        assert_eq!(pending, [0xc3]);
+    }
+
+    #[test]
+    fn read_until_keeps_data_on_error() {
+        let (tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        tx.send((Instant::now(), Message::Data("a".into())))
+            .unwrap();
+        tx.send((
+            Instant::now(),
+            Message::Error(PtyError::new(ErrorKind::Io, "boom")),
+        ))
+        .unwrap();
+
+        assert!(reader
+            .read_until("never", Duration::from_millis(100))
+            .is_err());
+        // the data read before the error is returned by the next read
+        assert_eq!(reader.read().unwrap(), Message::Data("a".into()));
+    }
+
+    #[test]
+    fn control_byte() {
+        assert_eq!(super::control_byte('C' as u32).unwrap(), 0x03);
+        assert_eq!(super::control_byte('d' as u32).unwrap(), 0x04);
+        assert_eq!(super::control_byte('?' as u32).unwrap(), 0x7f);
+        // U+0143 isn't truncated to 'C'
+        assert!(super::control_byte(0x143).is_err());
+        assert!(super::control_byte('1' as u32).is_err());
+    }
+
+    #[test]
+    fn interrupt_read() {
+        let (_tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        let clone = reader.clone();
+        let blocked = std::thread::spawn(move || {
+            clone
+                .read_timeout(Duration::from_secs(10))
+                .map_err(|err| err.to_string())
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        reader.interrupt();
+        let err = blocked.join().unwrap().unwrap_err();
+        assert_eq!(err, "the read was interrupted");
+        // the interrupt is consumed
+        assert_eq!(reader.read_timeout(Duration::ZERO).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn coalesce() {
+        use std::{io::Write, os::fd::FromRawFd};
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        let mut writer = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+        let coalesce = Coalesce {
+            window: Duration::from_millis(200),
+            fd: reader.try_clone().unwrap().into(),
+        };
+        let (tx, rx) = unbounded();
+        let tx = ReadSender {
+            tx,
+            ready: Arc::default(),
+        };
+        let writer_thread = std::thread::spawn(move || {
+            writer.write_all(b"a").unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            writer.write_all(b"b").unwrap();
+            // after the window, so it's sent separately
+            std::thread::sleep(Duration::from_millis(400));
+            writer.write_all(b"c").unwrap();
+        });
+        read_loop(
+            &mut reader,
+            &tx,
+            Decode::Strict,
+            &AtomicUsize::new(0),
+            None,
+            Some(&coalesce),
+            &mut |_| {},
+        );
+        writer_thread.join().unwrap();
+
+        let msgs: Vec<_> = rx.try_iter().map(|(_, msg)| msg).collect();
+        assert_eq!(
+            msgs,
+            [Message::Data("ab".into()), Message::Data("c".into())]
+        );
+    }
+
+    #[test]
+    fn free_cloned_reader() {
+        let (tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        drop(reader.clone());
+
+        tx.send((Instant::now(), Message::Data("a".into())))
+            .unwrap();
+        assert_eq!(reader.read().unwrap(), Message::Data("a".into()));
+    }
+
+    #[test]
+    fn c_pty_size_layout() {
+        assert_eq!(std::mem::size_of::<CPtySize>(), 8);
+        assert_eq!(std::mem::align_of::<CPtySize>(), 2);
+        let size = CPtySize {
+            rows: 1,
+            cols: 2,
+            pixel_width: 3,
+            pixel_height: 4,
+        };
+        assert_eq!(CPtySize::from(PtySize::from(size)), size);
+    }
+
+    #[test]
+    fn read_retries_transient_errors() {
+        struct FlakyReader(Vec<std::io::Result<&'static [u8]>>);
+        impl Read for FlakyReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let data = self.0.remove(0)?;
+                buf[..data.len()].copy_from_slice(data);
+                Ok(data.len())
+            }
+        }
+        let (tx, rx) = unbounded();
+        let tx = ReadSender {
+            tx,
+            ready: Arc::default(),
+        };
+        let mut reader = FlakyReader(vec![
+            Ok(b"a"),
+            Err(std::io::ErrorKind::Interrupted.into()),
+            Err(std::io::ErrorKind::WouldBlock.into()),
+            Ok(b"b"),
+        ]);
+        read_loop(
+            &mut reader,
+            &tx,
+            Decode::Strict,
+            &AtomicUsize::new(0),
+            None,
+            None,
+            &mut |_| {},
+        );
+
+        let msgs: Vec<_> = rx.try_iter().map(|(_, msg)| msg).collect();
+        assert_eq!(msgs, [Message::Data("a".into()), Message::Data("b".into())]);
+    }
+
+    #[test]
+    fn resolve_path() {
+        let command = Command {
+            clear_env: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(command.resolve_path(), None);
+        let command = Command {
+            clear_env: Some(true),
+            path: Some("/a".into()),
+            env: vec![("PATH".into(), "/b".into())],
+            ..Default::default()
+        };
+        assert_eq!(command.resolve_path(), Some("/b".into()));
+        let command = Command {
+            path: Some("/a".into()),
+            unset_env: Some(vec!["PATH".into()]),
+            ..Default::default()
+        };
+        assert_eq!(command.resolve_path(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_program() {
+        let cwd = std::path::Path::new("/");
+        assert_eq!(
+            utils::find_program("sh", Some("/nope:/bin".as_ref()), cwd),
+            Some("/bin/sh".into())
+        );
+        assert_eq!(utils::find_program("sh", None, cwd), None);
+        assert_eq!(
+            utils::find_program("bin/sh", None, cwd),
+            Some("/bin/sh".into())
+        );
+        // not executable
+        assert_eq!(
+            utils::find_program("passwd", Some("/etc".as_ref()), cwd),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bad_record_path_doesnt_spawn() {
+        let marker = std::env::temp_dir().join(format!("pty-record-{}", std::process::id()));
+        let result = Pty::create(Command {
+            cmd: "touch".into(),
+            args: vec![marker.to_str().unwrap().into()],
+            record_path: Some("/does-not-exist/out.cast".into()),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn reader_is_sync() {
+        // a blocking read runs on another thread while the host calls the non blocking functions
+        fn assert_sync<T: Send + Sync>() {}
+        assert_sync::<PtyReader>();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cancel_returns_calls_in_flight() {
+        let pty = Box::into_raw(Box::new(
+            Pty::create(Command {
+                cmd: "sleep".into(),
+                args: vec!["10".into()],
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        // like the host threads running the blocking calls
+        let addr = pty as usize;
+        let started = Instant::now();
+        let waiter = std::thread::spawn(move || {
+            let pty = unsafe { &*(addr as *const Pty) };
+            pty.exit.wait(Some(Duration::from_secs(5)))
+        });
+        let reader = std::thread::spawn(move || {
+            let pty = unsafe { &*(addr as *const Pty) };
+            loop {
+                match pty.read_timeout(Duration::from_secs(5)) {
+                    Ok(Some(Message::Data(_))) => continue,
+                    result => return result.map_err(|err| err.to_string()),
+                }
+            }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        unsafe { pty_cancel(pty, 1) };
+        assert!(waiter.join().unwrap());
+        assert!(!matches!(reader.join().unwrap(), Ok(None)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        unsafe { pty_close_nokill(pty) };
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_during_read() {
+        let pty = Box::into_raw(Box::new(
+            Pty::create(Command {
+                cmd: "sleep".into(),
+                args: vec!["10".into()],
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let addr = pty as usize;
+        let started = Instant::now();
+        // the blocking read runs on another thread while the signal is sent
+        let reader = std::thread::spawn(move || loop {
+            let mut result = 0;
+            match unsafe { pty_read_timeout(addr as *mut Pty, 5000, &mut result) } {
+                0 | 98 => drop(unsafe { CString::from_raw(result as _) }),
+                code => return code,
+            }
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        let mut result = 0;
+        // SIGTERM
+        assert_eq!(unsafe { pty_signal(pty, 15, &mut result) }, 0);
+        assert_eq!(reader.join().unwrap(), 99);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        unsafe { pty_close(pty) };
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn close_flush() {
+        let file = std::env::temp_dir().join(format!("pty-close-flush-{}", std::process::id()));
+        let pty = Pty::create(Command {
+            cmd: format!("head -n 1 > {}", file.display()),
+            shell: Some(Shell::Enabled(true)),
+            ..Default::default()
+        })
+        .unwrap();
+        pty.write_bytes(b"hello\n".to_vec()).unwrap();
+        unsafe { pty_close_flush(Box::into_raw(Box::new(pty)), 1000, 0) };
+
+        let mut content = String::new();
+        for _ in 0..50 {
+            content = std::fs::read_to_string(&file).unwrap_or_default();
+            if !content.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let _ = std::fs::remove_file(&file);
+        assert_eq!(content, "hello\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrapped_command_not_found() {
+        for command in [
+            Command {
+                detach: Some(true),
+                ..Default::default()
+            },
+            Command {
+                start_stopped: Some(true),
+                ..Default::default()
+            },
+            Command {
+                separate_stderr: Some(true),
+                ..Default::default()
+            },
+        ] {
+            let err = Pty::create(Command {
+                cmd: "does-not-exist".into(),
+                ..command
+            })
+            .err()
+            .unwrap();
+            let err = err.downcast_ref::<PtyError>().unwrap();
+            assert_eq!(err.kind, ErrorKind::NotFound);
+            assert_eq!(err.message, "command not found: does-not-exist");
+        }
+    }
+
+    #[test]
+    fn arg0_unsupported() {
+        let err = Pty::create(Command {
+            cmd: "sh".into(),
+            arg0: Some("-sh".into()),
+            ..Default::default()
+        })
+        .err()
+        .unwrap();
+        let err = err.downcast_ref::<PtyError>().unwrap();
+        assert_eq!(err.kind, ErrorKind::Unsupported);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collect_frees_bytes() {
+        let pty = Box::into_raw(Box::new(
+            Pty::create(Command {
+                cmd: "echo".into(),
+                args: vec!["hello".into()],
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let (mut data, mut data_len, mut exit_code, mut result) = (0, 0, 0, 0);
+        let code = unsafe {
+            pty_collect(
+                pty,
+                5000,
+                &mut data,
+                &mut data_len,
+                &mut exit_code,
+                &mut result,
+            )
+        };
+        assert_eq!(code, 0);
+        let output = unsafe { std::slice::from_raw_parts(data as *const u8, data_len) };
+        assert_eq!(output, b"hello\r\n");
+        unsafe { pty_free_bytes(data as _, data_len) };
+        unsafe { pty_close(pty) };
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nice_before_exec() {
+        // the command reads its own value right away, before a parent could have set it
+        let pty = Pty::create(Command {
+            cmd: "nice".into(),
+            nice: Some(utils::priority() + 5),
+            ..Default::default()
+        })
+        .unwrap();
+        let (output, code) = pty.collect(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(code, Some(0));
+        assert_eq!(output.trim(), (utils::priority() + 5).to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tty_name() {
+        let pty = Pty::create(Command {
+            cmd: "sh".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(pty.tty_name().unwrap().starts_with("/dev/"));
+    }
+
+    #[test]
+    fn last_data_with_end() {
+        let (tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        tx.send((Instant::now(), Message::Data("a".into())))
+            .unwrap();
+        assert_eq!(reader.read().unwrap(), Message::Data("a".into()));
+        assert!(!reader.ended());
+
+        tx.send((Instant::now(), Message::Data("b".into())))
+            .unwrap();
+        tx.send((Instant::now(), Message::End)).unwrap();
+        assert_eq!(reader.read().unwrap(), Message::Data("b".into()));
+        assert!(reader.ended());
+        assert_eq!(reader.read().unwrap(), Message::End);
+    }
+
+    #[test]
+    fn peek_keeps_data() {
+        let (tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        tx.send((Instant::now(), Message::Data("a".into())))
+            .unwrap();
+        assert_eq!(reader.peek(), ("a".into(), false));
+        tx.send((Instant::now(), Message::Data("b".into())))
+            .unwrap();
+        tx.send((Instant::now(), Message::End)).unwrap();
+        assert_eq!(reader.peek(), ("ab".into(), true));
+        assert_eq!(
+            reader.read_timeout(Duration::ZERO).unwrap(),
+            Some(Message::Data("ab".into()))
+        );
+        assert_eq!(reader.peek(), ("".into(), true));
+        assert_eq!(reader.read().unwrap(), Message::End);
+    }
+
+    #[test]
+    fn end_on_disconnect() {
+        let (tx, rx) = unbounded();
+        let reader = test_reader(rx);
+        assert_eq!(reader.read().unwrap(), Message::Data("".into()));
+        drop(tx);
+        assert_eq!(reader.read().unwrap(), Message::End);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_fd() {
+        let (tx, rx) = unbounded();
+        let ready = Arc::new(Ready::default());
+        let tx = ReadSender {
+            tx,
+            ready: ready.clone(),
+        };
+        let reader = PtyReader {
+            ready,
+            ..test_reader(rx)
+        };
+        let readable = |fd| {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+        };
+
+        // sent before the fd is created
+        tx.send((Instant::now(), Message::Data("a".into())))
+            .unwrap();
+        let fd = reader.readable_fd().unwrap();
+        assert!(readable(fd));
+        assert_eq!(reader.read().unwrap(), Message::Data("a".into()));
+        assert!(!readable(fd));
+
+        tx.send((Instant::now(), Message::End)).unwrap();
+        assert!(readable(fd));
+        assert_eq!(reader.read().unwrap(), Message::End);
+        assert!(readable(fd));
+    }
+
+    #[test]
+    fn ring_buffer_drops_oldest() {
+        let (tx, rx) = unbounded();
+        let tx = ReadSender {
+            tx,
+            ready: Arc::default(),
+        };
+        let pending_bytes = Arc::new(AtomicUsize::new(0));
+        let dropped_bytes = Arc::new(AtomicUsize::new(0));
+        let ring = RingBuffer {
+            rx_read: rx.clone(),
+            max_bytes: 600,
+            dropped_bytes: dropped_bytes.clone(),
+            held: Held::default(),
+        };
+        // read in chunks of 512 bytes
+        let output = "a".repeat(1536) + &"b".repeat(464);
+        read_loop(
+            &mut output.as_bytes(),
+            &tx,
+            Decode::Strict,
+            &pending_bytes,
+            Some(&ring),
+            None,
+            &mut |_| {},
+        );
+        assert_eq!(pending_bytes.load(Ordering::Relaxed), 464);
+
+        let reader = PtyReader {
+            pending_bytes,
+            dropped_bytes,
+            ..test_reader(rx)
+        };
+        assert_eq!(
+            reader.read().unwrap(),
+            Message::Data(format!("[1536 bytes dropped]\r\n{}", "b".repeat(464)))
+        );
+        assert_eq!(reader.pending_bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn ring_buffer_keeps_end_in_place() {
+        let (tx, rx) = unbounded();
+        let tx = ReadSender {
+            tx,
+            ready: Arc::default(),
+        };
+        let pending_bytes = Arc::new(AtomicUsize::new(0));
+        let dropped_bytes = Arc::new(AtomicUsize::new(0));
+        let held = Held::default();
+        let ring = RingBuffer {
+            rx_read: rx.clone(),
+            max_bytes: 2,
+            dropped_bytes: dropped_bytes.clone(),
+            held: held.clone(),
+        };
+        // the end sent by the waiter before the last output, like after end_drain_ms
+        tx.send((Instant::now(), Message::End)).unwrap();
+        // read as "ab" then "c", the end is taken out of the channel to drop "ab"
+        read_loop(
+            &mut "ab".as_bytes().chain("c".as_bytes()),
+            &tx,
+            Decode::Strict,
+            &pending_bytes,
+            Some(&ring),
+            None,
+            &mut |_| {},
+        );
+        assert_eq!(held.lock().len(), 1);
+
+        let reader = PtyReader {
+            pending_bytes,
+            dropped_bytes,
+            held,
+            ..test_reader(rx)
+        };
+        // the output after the end isn't lost
+        assert_eq!(
+            reader.read().unwrap(),
+            Message::Data("[2 bytes dropped]\r\nc".into())
+        );
+        assert_eq!(reader.read().unwrap(), Message::End);
+    }
+
+    #[test]
+    fn reader_panic() {
+        struct PanicReader;
+        impl Read for PanicReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                panic!("boom")
+            }
+        }
+
+        let (tx, rx) = unbounded();
+        let tx = ReadSender {
+            tx,
+            ready: Arc::default(),
+        };
+        let pending_bytes = Arc::new(AtomicUsize::new(0));
+        let result = catch_panic("reader", &tx, || {
+            read_loop(
+                &mut PanicReader,
+                &tx,
+                Decode::Strict,
+                &pending_bytes,
+                None,
+                None,
+                &mut |_| {},
+            )
+        });
+        assert!(result.is_err());
+
+        let reader = PtyReader {
+            pending_bytes,
+            ..test_reader(rx)
+        };
+        let err = reader.read().unwrap_err();
+        assert_eq!(err.to_string(), "the reader thread panicked: boom");
+    }
+
+    #[test]
+    fn error_with_null_byte() {
+        let err = boxed_error_to_cstring("a\0b".into());
+        assert_eq!(err.to_str().unwrap(), r#"{"kind":0,"message":"a\u0000b"}"#);
+    }
+
+    #[test]
+    fn length_prefixed_args() {
+        let mut buf = Vec::new();
+        for arg in ["-c", "", "é"] {
+            buf.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+            buf.extend_from_slice(arg.as_bytes());
+        }
+        assert_eq!(decode_length_prefixed(&buf).unwrap(), ["-c", "", "é"]);
+        assert!(decode_length_prefixed(&buf[..buf.len() - 1]).is_err());
+        assert!(decode_length_prefixed(&[1, 0]).is_err());
+    }
+
+    #[test]
+    fn env_shapes() {
+        let command: Command =
+            serde_json::from_str(r#"{"cmd":"a","args":[],"env":[["B","1"],["A","2"],["B","3"]]}"#)
+                .unwrap();
+        assert_eq!(
+            command.env,
+            [
+                ("B".into(), "1".into()),
+                ("A".into(), "2".into()),
+                ("B".into(), "3".into())
+            ]
+        );
+
+        let command: Command =
+            serde_json::from_str(r#"{"cmd":"a","args":[],"env":{"B":"1","A":"2"}}"#).unwrap();
+        assert_eq!(
+            command.env,
+            [("B".into(), "1".into()), ("A".into(), "2".into())]
+        );
+    }
 }
@@ -0,0 +1,27 @@
+use parking_lot::{const_mutex, Mutex};
+use std::{ffi::CString, os::raw::c_char};
+
+pub type LogCallback = extern "C" fn(level: i32, msg: *const c_char);
+
+static LOG_CALLBACK: Mutex<Option<LogCallback>> = const_mutex(None);
+
+/// The numeric values are part of the ffi api, don't change them
+#[derive(Debug, Clone, Copy)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+}
+
+pub fn set_callback(callback: Option<LogCallback>) {
+    *LOG_CALLBACK.lock() = callback;
+}
+
+/// Sends the message to the registered callback, does nothing if there is none
+pub fn log(level: LogLevel, msg: &str) {
+    // don't hold the lock while calling the callback
+    let Some(callback) = *LOG_CALLBACK.lock() else {
+        return;
+    };
+    let msg = CString::new(msg.replace('\0', "")).expect("null bytes are removed");
+    callback(level as i32, msg.as_ptr());
+}
@@ -0,0 +1,129 @@
+use crate::Result;
+use std::sync::OnceLock;
+
+/// A file descriptor that is readable while the readers have something to read
+///
+/// It lets an event loop wait for the output instead of polling, on windows it's a manual reset event
+/// that is signaled instead. It's only created when it's requested, until then setting it does nothing
+#[derive(Default)]
+pub struct Ready(OnceLock<Signal>);
+
+impl Ready {
+    /// Returns the fd, it's created by the first call
+    pub fn fd(&self) -> Result<i32> {
+        if let Some(signal) = self.0.get() {
+            return Ok(signal.fd());
+        }
+        // if another thread created it first, this one is dropped
+        let _ = self.0.set(Signal::new()?);
+        Ok(self.0.get().expect("the signal was just set").fd())
+    }
+
+    /// Makes the fd readable
+    pub fn set(&self) {
+        if let Some(signal) = self.0.get() {
+            signal.set();
+        }
+    }
+
+    /// Makes the fd not readable, until the next set
+    pub fn clear(&self) {
+        if let Some(signal) = self.0.get() {
+            signal.clear();
+        }
+    }
+}
+
+#[cfg(unix)]
+struct Signal {
+    read: i32,
+    write: i32,
+}
+
+#[cfg(unix)]
+impl Signal {
+    fn new() -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let signal = Self {
+            read: fds[0],
+            write: fds[1],
+        };
+        for fd in fds {
+            // set never blocks, a full pipe is readable anyway
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags == -1
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1
+                || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1
+            {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(signal)
+    }
+
+    fn fd(&self) -> i32 {
+        self.read
+    }
+
+    fn set(&self) {
+        unsafe { libc::write(self.write, [1u8].as_ptr() as _, 1) };
+    }
+
+    fn clear(&self) {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.read, buf.as_mut_ptr() as _, buf.len()) } > 0 {}
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Signal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(windows)]
+struct Signal(winapi::um::winnt::HANDLE);
+// the handle can be used from any thread
+#[cfg(windows)]
+unsafe impl Send for Signal {}
+#[cfg(windows)]
+unsafe impl Sync for Signal {}
+
+#[cfg(windows)]
+impl Signal {
+    fn new() -> Result<Self> {
+        use winapi::{shared::minwindef::TRUE, um::synchapi::CreateEventW};
+        let event = unsafe { CreateEventW(std::ptr::null_mut(), TRUE, 0, std::ptr::null()) };
+        if event.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self(event))
+    }
+
+    // NOTE: handles only use the lower 32 bits, so they can be passed as an i32
+    fn fd(&self) -> i32 {
+        self.0 as isize as i32
+    }
+
+    fn set(&self) {
+        unsafe { winapi::um::synchapi::SetEvent(self.0) };
+    }
+
+    fn clear(&self) {
+        unsafe { winapi::um::synchapi::ResetEvent(self.0) };
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Signal {
+    fn drop(&mut self) {
+        unsafe { winapi::um::handleapi::CloseHandle(self.0) };
+    }
+}
@@ -0,0 +1,83 @@
+use crate::Result;
+use std::{
+    ffi::{CString, OsString},
+    fs::{File, OpenOptions},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt, io::AsRawFd},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// makes the fifo paths unique inside the process
+static FIFO_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A fifo the child's stderr is redirected to
+///
+/// The pty's stdio is set by portable_pty and the other file descriptors are closed before exec,
+/// so the child opens the fifo by path instead
+pub struct StderrFifo {
+    path: PathBuf,
+    // keeps the fifo open until the child exits, so the reader doesn't see EOF
+    // before the child opens it
+    _writer: File,
+}
+impl StderrFifo {
+    /// Creates the fifo and returns it with its read end
+    pub fn create() -> Result<(Self, File)> {
+        let path = std::env::temp_dir().join(format!(
+            "deno-pty-stderr-{}-{}",
+            std::process::id(),
+            FIFO_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let open = || -> Result<(File, File)> {
+            // opening the read end blocks until there is a writer, unless it's non blocking
+            let reader = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)?;
+            let writer = OpenOptions::new().write(true).open(&path)?;
+            // the reader thread blocks on reads
+            let fd = reader.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags == -1
+                || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1
+            {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok((reader, writer))
+        };
+        match open() {
+            Ok((reader, writer)) => Ok((
+                Self {
+                    path,
+                    _writer: writer,
+                },
+                reader,
+            )),
+            Err(err) => {
+                let _ = std::fs::remove_file(&path);
+                Err(err)
+            }
+        }
+    }
+
+    /// Wraps the command in a shell that redirects its stderr to the fifo before running it
+    pub fn wrap_argv(&self, argv: &mut Vec<OsString>) {
+        let command = std::mem::take(argv);
+        argv.extend([
+            "/bin/sh".into(),
+            "-c".into(),
+            r#"exec 2>"$0"; exec "$@""#.into(),
+            self.path.clone().into(),
+        ]);
+        argv.extend(command);
+    }
+}
+impl Drop for StderrFifo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::Result;
+use std::os::unix::io::RawFd;
+
+fn get_termios(fd: RawFd) -> Result<libc::termios> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { termios.assume_init() })
+}
+
+/// Reads the termios of the terminal, lets `f` modify it and applies it immediately
+pub fn update_termios(fd: RawFd, f: impl FnOnce(&mut libc::termios)) -> Result<()> {
+    let mut termios = get_termios(fd)?;
+    f(&mut termios);
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// The character that sends EOF in canonical mode, 0 if it's disabled
+pub fn eof_char(fd: RawFd) -> Result<u8> {
+    Ok(get_termios(fd)?.c_cc[libc::VEOF])
+}
+
+/// Raw mode disables line buffering, echo and signal generating characters
+pub fn set_raw(termios: &mut libc::termios, raw: bool) {
+    let flags = libc::ICANON | libc::ECHO | libc::ISIG;
+    if raw {
+        termios.c_lflag &= !flags;
+    } else {
+        termios.c_lflag |= flags;
+    }
+}
+
+/// Output processing translates \n to \r\n, it's enabled by default
+pub fn set_onlcr(termios: &mut libc::termios, onlcr: bool) {
+    if onlcr {
+        termios.c_oflag |= libc::OPOST | libc::ONLCR;
+    } else {
+        termios.c_oflag &= !libc::ONLCR;
+    }
+}
+
+pub fn set_echo(termios: &mut libc::termios, echo: bool) {
+    if echo {
+        termios.c_lflag |= libc::ECHO;
+    } else {
+        termios.c_lflag &= !libc::ECHO;
+    }
+}
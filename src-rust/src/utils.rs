@@ -1,11 +1,21 @@
-use crate::Result;
-use serde::{de::DeserializeOwned, Serialize};
+use crate::error::SerializedError;
+use crate::{Decode, Result};
+use serde::{
+    de::{DeserializeOwned, MapAccess, SeqAccess, Visitor},
+    Deserializer, Serialize,
+};
 use std::os::raw::c_char;
-use std::{ffi::CString, mem::ManuallyDrop};
+use std::str::Utf8Error;
+use std::{
+    ffi::{CString, OsStr},
+    mem::ManuallyDrop,
+    path::{Path, PathBuf},
+};
 
 /// # Safety
 /// expects
 /// - valid ptr to a T encoded as CString encoding a JSON value
+///
 /// returns a T
 /// This function doens't consume the CString
 pub unsafe fn cstr_to_type<T: DeserializeOwned>(cstr: *mut c_char) -> Result<T> {
@@ -17,6 +27,262 @@ pub fn type_to_cstr<T: Serialize>(t: &T) -> Result<CString> {
     Ok(CString::new(serde_json::to_string(&t)?)?)
 }
 
+/// Encodes the error as a JSON object `{ kind, message }`
+///
+/// Never panics since it's called at the ffi boundary, null bytes in the message are escaped by JSON
 pub fn boxed_error_to_cstring(err: Box<dyn std::error::Error>) -> CString {
-    CString::new(err.to_string()).expect("failed to create cstring")
+    type_to_cstr(&SerializedError::from(err)).unwrap_or_else(|_| {
+        CString::new(r#"{"kind":0,"message":"failed to encode the error"}"#).unwrap_or_default()
+    })
+}
+
+/// Accepts the environment as a list of `[key, value]` pairs or as an object
+///
+/// The order is preserved in both cases, and the list can contain duplicate keys
+pub fn deserialize_env<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<(String, String)>, D::Error> {
+    struct EnvVisitor;
+    impl<'de> Visitor<'de> for EnvVisitor {
+        type Value = Vec<(String, String)>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a list of [key, value] pairs or an object")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            let mut env = Vec::new();
+            while let Some(pair) = seq.next_element()? {
+                env.push(pair);
+            }
+            Ok(env)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            let mut env = Vec::new();
+            while let Some(pair) = map.next_entry()? {
+                env.push(pair);
+            }
+            Ok(env)
+        }
+    }
+    deserializer.deserialize_any(EnvVisitor)
+}
+
+/// Decodes a list of strings, each one is prefixed by its length in bytes as a little endian u32
+pub fn decode_length_prefixed(mut buf: &[u8]) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    while !buf.is_empty() {
+        let (len, rest) = buf
+            .split_first_chunk::<4>()
+            .ok_or("the length of a string is truncated")?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err("a string is longer than the buffer".into());
+        }
+        let (string, rest) = rest.split_at(len);
+        strings.push(std::str::from_utf8(string)?.to_owned());
+        buf = rest;
+    }
+    Ok(strings)
+}
+
+/// Decodes the complete utf8 sequences in `pending` and removes them from it
+///
+/// An incomplete sequence at the end is kept in `pending`, so it can be completed by the next read
+pub fn decode_utf8(
+    pending: &mut Vec<u8>,
+    decode: Decode,
+) -> std::result::Result<String, Utf8Error> {
+    let complete = pending.len() - incomplete_suffix_len(pending);
+    let bytes = &pending[..complete];
+    let data = match decode {
+        Decode::Strict => std::str::from_utf8(bytes)?.to_owned(),
+        Decode::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        Decode::Hex => {
+            let mut data = String::with_capacity(bytes.len());
+            for chunk in bytes.utf8_chunks() {
+                data.push_str(chunk.valid());
+                for byte in chunk.invalid() {
+                    data.push_str(&format!("\\x{byte:02x}"));
+                }
+            }
+            data
+        }
+    };
+    pending.drain(..complete);
+    Ok(data)
+}
+
+/// Returns the length of the truncated utf8 sequence at the end of `bytes`
+fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+    for i in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - i];
+        // continuation byte
+        if byte & 0b1100_0000 == 0b1000_0000 {
+            continue;
+        }
+        let width = match byte {
+            0b1100_0000..=0b1101_1111 => 2,
+            0b1110_0000..=0b1110_1111 => 3,
+            0b1111_0000..=0b1111_0111 => 4,
+            _ => 1,
+        };
+        return if width > i { i } else { 0 };
+    }
+    0
+}
+
+/// Blocks until the process exits, without reaping it so it can still be waited on
+///
+/// Returns the signal that terminated the process, if any
+#[cfg(unix)]
+pub fn wait_exit_signal(pid: u32) -> Option<i32> {
+    let mut info = std::mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+    loop {
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                info.as_mut_ptr(),
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if ret == 0 {
+            break;
+        }
+        if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            return None;
+        }
+    }
+    let info = unsafe { info.assume_init() };
+    match info.si_code {
+        libc::CLD_KILLED | libc::CLD_DUMPED => Some(unsafe { info.si_status() }),
+        _ => None,
+    }
+}
+
+/// The nice value of the current process, the processes it starts inherit it
+#[cfg(unix)]
+pub fn priority() -> i32 {
+    // it can't fail for the current process, and -1 is a valid value
+    unsafe { libc::getpriority(libc::PRIO_PROCESS as _, 0) }
+}
+
+/// Sets the priority class closest to the nice value
+#[cfg(windows)]
+pub fn set_priority(pid: u32, nice: i32) -> Result<()> {
+    use winapi::{
+        shared::minwindef::FALSE,
+        um::{
+            handleapi::CloseHandle,
+            processthreadsapi::{OpenProcess, SetPriorityClass},
+            winbase::{
+                ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS, HIGH_PRIORITY_CLASS,
+                IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS,
+            },
+            winnt::PROCESS_SET_INFORMATION,
+        },
+    };
+    let class = match nice {
+        ..=-15 => HIGH_PRIORITY_CLASS,
+        -14..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+        15.. => IDLE_PRIORITY_CLASS,
+    };
+    let process = unsafe { OpenProcess(PROCESS_SET_INFORMATION, FALSE, pid) };
+    if process.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let set = unsafe { SetPriorityClass(process, class) };
+    unsafe { CloseHandle(process) };
+    if set == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Whether the environment variable is PATH, the names are case insensitive on windows
+pub fn is_path_key(key: &str) -> bool {
+    if cfg!(windows) {
+        key.eq_ignore_ascii_case("PATH")
+    } else {
+        key == "PATH"
+    }
+}
+
+/// Finds the program like the pty does before starting it
+///
+/// Programs with a path separator are resolved against cwd, the others are searched in path
+pub fn find_program(program: &str, path: Option<&OsStr>, cwd: &Path) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return executable(&cwd.join(program));
+    }
+    std::env::split_paths(path?)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .find_map(|dir| executable(&cwd.join(dir).join(program)))
+}
+
+#[cfg(unix)]
+fn executable(candidate: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = std::fs::metadata(candidate).ok()?;
+    (metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .then(|| candidate.to_path_buf())
+}
+
+/// Tries the extensions of PATHEXT when the program doesn't have one
+#[cfg(windows)]
+fn executable(candidate: &Path) -> Option<PathBuf> {
+    if candidate.extension().is_some() && candidate.is_file() {
+        return Some(candidate.to_path_buf());
+    }
+    let extensions = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+    extensions
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| {
+            let mut candidate = candidate.as_os_str().to_os_string();
+            candidate.push(extension);
+            PathBuf::from(candidate)
+        })
+        .find(|candidate| candidate.is_file())
+}
+
+/// The path of the slave of the pty master fd
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn ptsname(fd: i32) -> Result<String> {
+    let mut buf = [0 as c_char; 128];
+    let ret = unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret).into());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }
+        .to_str()?
+        .to_owned())
+}
+
+/// ptsname_r isn't available on the other platforms, ptsname returns a static buffer so the calls are serialized
+#[cfg(all(
+    unix,
+    not(any(target_os = "linux", target_os = "android", target_os = "freebsd"))
+))]
+pub fn ptsname(fd: i32) -> Result<String> {
+    static LOCK: parking_lot::Mutex<()> = parking_lot::Mutex::new(());
+    let _lock = LOCK.lock();
+    let name = unsafe { libc::ptsname(fd) };
+    if name.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(name) }
+        .to_str()?
+        .to_owned())
 }
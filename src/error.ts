@@ -0,0 +1,43 @@
+/**
+ * The kind of a {@linkcode PtyError}.
+ */
+export enum ErrorKind {
+  Other = 0,
+  /** A value sent to the library isn't valid JSON or doesn't have the expected shape. */
+  InvalidJson = 1,
+  /** A string isn't valid utf8 or contains a null byte. */
+  InvalidString = 2,
+  NotFound = 3,
+  PermissionDenied = 4,
+  /** Any other io error. */
+  Io = 5,
+  /** A background thread of the pty exited. */
+  Disconnected = 6,
+  /** The operation isn't supported on this platform. */
+  Unsupported = 7,
+  /** An argument passed to the library is invalid. */
+  InvalidInput = 8,
+  /** The process was killed because it ran longer than the timeout or didn't output anything for too long. */
+  TimedOut = 9,
+  /** A blocking read was interrupted with {@linkcode Pty.interruptRead}. */
+  Interrupted = 10,
+}
+
+/**
+ * An error returned by the pty library.
+ */
+export class PtyError extends Error {
+  /** The kind of the error, can be used to handle specific errors. */
+  kind: ErrorKind;
+
+  /**
+   * Creates a new PtyError.
+   * @param kind - The kind of the error.
+   * @param message - The error message.
+   */
+  constructor(kind: ErrorKind, message: string) {
+    super(message);
+    this.name = "PtyError";
+    this.kind = kind;
+  }
+}
@@ -8,10 +8,202 @@ export interface Command {
   cmd: string;
   /** The arguments for the command. */
   args: string[];
-  /** The environment variables for the command. */
-  env: [string, string][];
+  /**
+   * The environment variables for the command, applied in order.
+   * A list of pairs can repeat a key, the last value wins.
+   *
+   * The environment is built in this order, each step overrides the previous ones:
+   * 1. the base: all the variables of the current process, empty with `clear_env`
+   * 2. `path` replaces PATH, a default PATH is used if the current process doesn't have one (not with `clear_env`)
+   * 3. the `TERM`, `LANG` and `COLORTERM` defaults of `default_env`, only if they aren't inherited
+   * 4. `COLUMNS` and `LINES` of the initial size, with `default_env`
+   * 5. `env`
+   * 6. `unset_env` removals
+   */
+  env: [string, string][] | Record<string, string>;
   /** The working directory for the command. defaults to the current working directory. */
   cwd?: string;
+  /**
+   * Start the command with an empty environment, only the variables in `env` are set.
+   * PATH is not inherited, so `cmd` should be an absolute path or PATH should be set with `path`.
+   * NOTE: on windows some system variables may still be set.
+   */
+  clear_env?: boolean;
+  /**
+   * The PATH of the process instead of the PATH of the current process, it's also used to find `cmd`.
+   * It's set even with `clear_env`. Without it, a default PATH is used if the current process doesn't have one.
+   */
+  path?: string;
+  /**
+   * How long to wait for the end of the output after the process exits, in milliseconds. defaults to 100.
+   * On unix the exit is reported as soon as all the output is read, the wait is only reached if a child
+   * process keeps the pty open. On windows the whole wait is needed to get the trailing output.
+   * Set it to 0 to disable the wait, output that arrives after the exit is detected may be lost.
+   */
+  end_drain_ms?: number;
+  /**
+   * How to handle output that isn't valid utf8. defaults to "strict".
+   * - "strict": invalid utf8 stops the output
+   * - "lossy": invalid utf8 is replaced with the replacement character U+FFFD
+   * - "hex": each invalid byte is replaced with `\xNN` (two lowercase hex digits), for inspecting binary output.
+   *   The valid text, including backslashes, is kept as is, so a `\xNN` written by the process looks the same.
+   */
+  decode?: "strict" | "lossy" | "hex";
+  /**
+   * How many chunks of output (up to 512 bytes each) can be buffered before the pty stops reading
+   * from the process, which blocks it until the output is consumed. defaults to 1024.
+   */
+  read_channel_capacity?: number;
+  /**
+   * How many writes can be queued before `write` waits for the process to read its input.
+   * unbounded by default. Use `tryWrite` to get notified instead of waiting when it's full.
+   */
+  write_channel_capacity?: number;
+  /**
+   * Run `cmd` as a command line through a shell, for example `ls -la | grep foo`.
+   * `true` uses `/bin/sh -c` on unix and `cmd.exe /C` on windows, a string selects the shell to use.
+   * `args` are passed to the shell after the command line, on unix they are available as `$0`, `$1`, ...
+   */
+  shell?: boolean | string;
+  /**
+   * Set `TERM=xterm-256color` and a UTF-8 `LANG` (on unix) so programs render unicode correctly. defaults to true.
+   * `COLUMNS` and `LINES` are set to the initial size of the pty, they aren't updated on resize.
+   * The inherited `TERM`, `LANG` and `COLORTERM` are kept, and variables in `env` take precedence. Not applied with `clear_env`.
+   */
+  default_env?: boolean;
+  /**
+   * Set `COLORTERM=truecolor` with the `default_env` variables, for programs that check it before using 24-bit colors.
+   * defaults to false.
+   */
+  truecolor?: boolean;
+  /** Environment variables to remove, applied after all the other environment options. */
+  unset_env?: string[];
+  /**
+   * Whether the terminal echoes the written data back to the output. defaults to true.
+   * Disable it to write passwords without reading them back.
+   * NOTE: only supported on unix, ignored on windows. The process can still change it.
+   */
+  echo?: boolean;
+  /**
+   * Whether the terminal translates `\n` to `\r\n` in the output of the process. defaults to true.
+   * Disable it to read the output with the newlines the process wrote.
+   * NOTE: only supported on unix, ignored on windows. The process can still change it.
+   */
+  onlcr?: boolean;
+  /** The initial size of the pty, the process sees it from the start. defaults to 24 rows and 80 cols. */
+  size?: PtySize;
+  /**
+   * Redirect stderr to a pipe read with `readStderr` instead of the pty. defaults to false.
+   * The process sees that stderr isn't a terminal (isatty returns false), so it may change how it writes to it.
+   * NOTE: only supported on unix, creating the pty fails on windows.
+   */
+  separate_stderr?: boolean;
+  /**
+   * Start the process as the leader of a new session and process group, with the pty as its controlling terminal.
+   * defaults to true, this is needed for Ctrl-C and job control to work. Disabling it isn't supported.
+   * NOTE: ignored on windows.
+   */
+  setsid?: boolean;
+  /**
+   * Make the pty the controlling terminal of the process, so it can open `/dev/tty` and receive the terminal signals.
+   * defaults to true, disabling it isn't supported since portable-pty always does it before exec.
+   * NOTE: ignored on windows.
+   */
+  set_controlling_terminal?: boolean;
+  /**
+   * The umask of the process, for example `0o022`. defaults to the umask of the current process.
+   * NOTE: ignored on windows.
+   */
+  umask?: number;
+  /**
+   * The nice value of the process, from -20 (highest priority) to 19 (lowest). Lowering it usually needs privileges.
+   * On unix `cmd` is started through `nice`, so the value is set before it runs. If it can't be lowered,
+   * `nice` prints a warning in the output and `cmd` runs with the inherited value.
+   * NOTE: on windows it's mapped to the closest priority class, set right after the process starts.
+   */
+  nice?: number;
+  /**
+   * Kill the process if it's still running after this long, in milliseconds.
+   * The next read after the remaining output throws a `PtyError` with the `TimedOut` kind,
+   * then the pty reports the exit as usual.
+   */
+  timeout_ms?: number;
+  /**
+   * Kill the process if it doesn't output anything for this long, in milliseconds.
+   * Useful to stop a process that hangs waiting for input, it's reported like `timeout_ms`.
+   */
+  idle_timeout_ms?: number;
+  /**
+   * The signal sent to the process group by `close`, for example 1 for SIGHUP to let the process shut down gracefully.
+   * defaults to killing the process and its children.
+   * NOTE: on windows only the signals supported by `signal` can be used.
+   */
+  close_signal?: number;
+  /** Keep all the output of the process, so it can be read with `transcript` for debugging. defaults to false. */
+  record?: boolean;
+  /** Only keep the last bytes of the recorded output, the older output is dropped. defaults to no limit. */
+  record_max_bytes?: number;
+  /**
+   * Write the output to this file as an asciinema v2 recording, it can be replayed with `asciinema play`.
+   * The header contains the initial size of the pty.
+   */
+  record_path?: string;
+  /**
+   * Keep at most this many bytes of unread output, the oldest output is dropped instead of blocking the process.
+   * The next read starts with `[<n> bytes dropped]\r\n` when output was dropped. `read_channel_capacity` is ignored.
+   */
+  max_buffered_bytes?: number;
+  /**
+   * Batch the output read during this many milliseconds (or until 64KiB) into one chunk, for programs that
+   * write a lot of small chunks. Adds up to this much latency to the output.
+   * NOTE: ignored on windows.
+   */
+  coalesce_ms?: number;
+  /**
+   * The line ending appended by `writeLine`. defaults to "lf".
+   * - "lf": `\n`, enough for programs that read lines since the terminal translates the enter key to it
+   * - "cr": `\r`, what a terminal sends for the enter key, for programs in raw mode that only accept it
+   */
+  line_ending?: "lf" | "cr";
+  /**
+   * The pty implementation used on windows. defaults to "conpty".
+   * "winpty" isn't supported, portable-pty only implements conpty, creating the pty fails with it.
+   * NOTE: ignored on unix.
+   */
+  backend?: "conpty" | "winpty";
+  /**
+   * Writes bigger than this are split in chunks of this size, in bytes. defaults to 4096.
+   * The chunks are written in order and flushed one by one, so a big paste reaches the process
+   * progressively and `pendingWrites` counts the chunks left.
+   */
+  write_chunk_size?: number;
+  /**
+   * Run `cmd` through a `/bin/sh` that stops itself with SIGSTOP and execs `cmd` once it's continued.
+   * The pty library has no hook to stop the process before exec, so while it's stopped the pid is the
+   * one of the shell: a debugger attaching then sees `/bin/sh`, not `cmd`, and should follow the exec.
+   * Send SIGCONT with `signalGroup` to start it. Unix only, creating the pty fails on windows.
+   */
+  start_stopped?: boolean;
+  /**
+   * File descriptors to keep open in the process, for example a socket passed on fd 3.
+   * Not supported yet: the pty library closes the file descriptors above 2 before running `cmd`,
+   * so creating the pty fails if it's not empty.
+   */
+  inherit_fds?: number[];
+  /**
+   * The name the process sees as argv[0] instead of `cmd`, for example `-bash` to start a login shell.
+   * Not supported yet: the pty library always passes the program as argv[0] and `exec -a` isn't
+   * available in every /bin/sh, so creating the pty fails if it's set.
+   */
+  arg0?: string;
+  /**
+   * Start `cmd` in the background so it keeps running after the pty is closed, for launching daemons.
+   * The process of the pty is a shell that prints the pid of the detached command and exits.
+   * The command ignores SIGHUP, its input and output are redirected to /dev/null and it stays in the
+   * process group of the shell, but closing the pty doesn't kill it.
+   * Unix only, creating the pty fails on windows which has no equivalent.
+   */
+  detach?: boolean;
 }
 
 /**
@@ -29,29 +221,235 @@ export interface PtySize {
 }
 
 const SYMBOLS = {
-  pty_create: { parameters: ["buffer", "buffer"], result: "i8" },
+  pty_create: { parameters: ["buffer", "buffer", "buffer"], result: "i8" },
+  pty_validate_command: { parameters: ["buffer", "buffer"], result: "i8" },
+  pty_open: { parameters: ["buffer", "buffer"], result: "i8" },
+  pty_spawn: {
+    parameters: ["pointer", "buffer", "buffer", "buffer"],
+    result: "i8",
+  },
+  pty_open_close: { parameters: ["pointer"], result: "void" },
   pty_read: {
     parameters: ["pointer", "buffer"],
     result: "i8",
     nonblocking: true,
   },
+  pty_peek: { parameters: ["pointer", "buffer"], result: "i8" },
+  pty_drain: { parameters: ["pointer", "buffer", "buffer"], result: "i8" },
+  pty_clone_reader: { parameters: ["pointer", "buffer"], result: "i8" },
+  pty_reader_read: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_reader_free: { parameters: ["pointer"], result: "void" },
+  pty_read_stderr: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_read_timed: {
+    parameters: ["pointer", "buffer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_read_timeout: {
+    parameters: ["pointer", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_interrupt_read: {
+    parameters: ["pointer"],
+    result: "void",
+  },
+  pty_read_into: {
+    parameters: ["pointer", "buffer", "usize", "buffer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_read_until: {
+    parameters: ["pointer", "buffer", "usize", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_read_until_regex: {
+    parameters: ["pointer", "buffer", "usize", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_exchange: {
+    parameters: [
+      "pointer",
+      "buffer",
+      "usize",
+      "buffer",
+      "usize",
+      "u64",
+      "buffer",
+    ],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_collect: {
+    parameters: ["pointer", "i64", "buffer", "buffer", "buffer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_free_bytes: { parameters: ["pointer", "usize"], result: "void" },
+  pty_transcript: {
+    parameters: ["pointer", "buffer", "buffer", "buffer"],
+    result: "i8",
+  },
+  pty_pending_bytes: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_stats: {
+    parameters: ["pointer", "buffer", "buffer"],
+    result: "i8",
+  },
   pty_write: {
     parameters: ["pointer", "buffer", "buffer"],
     result: "i8",
     nonblocking: true,
   },
-  pty_get_size: {
+  pty_write_bytes: {
+    parameters: ["pointer", "buffer", "usize", "buffer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_try_write: {
+    parameters: ["pointer", "buffer", "usize", "buffer"],
+    result: "i8",
+  },
+  pty_write_file: {
+    parameters: ["pointer", "buffer", "usize", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_write_paste: {
+    parameters: ["pointer", "buffer", "usize", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_write_line: {
+    parameters: ["pointer", "buffer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_write_timeout: {
+    parameters: ["pointer", "buffer", "usize", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_pending_writes: {
     parameters: ["pointer", "buffer"],
     result: "i8",
   },
-  pty_resize: {
+  pty_send_control: {
+    parameters: ["pointer", "u32", "buffer"],
+    result: "i8",
+  },
+  pty_flush: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_flush_timeout: {
+    parameters: ["pointer", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_close_stdin: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_reopen_stdin: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_get_size_raw: {
+    parameters: ["pointer", "buffer", "buffer", "buffer", "buffer", "buffer"],
+    result: "i8",
+  },
+  pty_set_raw: {
+    parameters: ["pointer", "bool", "buffer"],
+    result: "i8",
+  },
+  pty_ttyname: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_readable_fd: {
+    parameters: ["pointer", "buffer", "buffer"],
+    result: "i8",
+  },
+  pty_slave_fd: {
+    parameters: ["pointer", "buffer", "buffer"],
+    result: "i8",
+  },
+  pty_resize_raw: {
+    parameters: ["pointer", "u16", "u16", "u16", "u16", "buffer"],
+    result: "i8",
+  },
+  pty_resize_verified: {
+    parameters: ["pointer", "u16", "u16", "u16", "u16", "buffer"],
+    result: "i8",
+  },
+  pty_signal: {
+    parameters: ["pointer", "i32", "buffer"],
+    result: "i8",
+  },
+  pty_signal_group: {
+    parameters: ["pointer", "i32", "buffer"],
+    result: "i8",
+  },
+  pty_terminate: {
+    parameters: ["pointer", "u64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_wait: {
+    parameters: ["pointer", "i64", "buffer"],
+    result: "i8",
+    nonblocking: true,
+  },
+  pty_is_alive: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_has_ended: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_exit_code: {
     parameters: ["pointer", "buffer", "buffer"],
     result: "i8",
   },
+  pty_exit_signal: {
+    parameters: ["pointer", "buffer"],
+    result: "i8",
+  },
+  pty_set_exit_callback: {
+    parameters: ["pointer", "function"],
+    result: "void",
+  },
+  pty_set_log_callback: {
+    parameters: ["function"],
+    result: "void",
+  },
+  pty_cancel: {
+    parameters: ["pointer", "u8"],
+    result: "void",
+  },
   pty_close: {
     parameters: ["pointer"],
     result: "void",
   },
+  pty_close_nokill: {
+    parameters: ["pointer"],
+    result: "void",
+  },
 } satisfies Deno.ForeignLibraryInterface;
 
 export async function instantiate(): Promise<
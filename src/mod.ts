@@ -1,36 +1,238 @@
 import { type Command, instantiate, type PtySize } from "./ffi.ts";
+import { ErrorKind, PtyError } from "./error.ts";
 import {
   createPtrFromBuffer,
   decodeCstring,
-  decodeJsonCstring,
+  decodeError,
   encodeCstring,
   encodeJsonCstring,
 } from "./utils.ts";
 
+const ENCODER = new TextEncoder();
+const DECODER = new TextDecoder();
+
 // NOTE: consier exporting this, so the user decides when to instantiate
 // NOTE(2): The Libary should remain alive as long as the program is running
 const LIBRARY = await instantiate();
 
+/** The level of a message passed to the log callback */
+export enum LogLevel {
+  Error = 0,
+  Warn = 1,
+}
+
+let logCallback: Deno.UnsafeCallback | undefined;
+
+// set by OpenPty, so Pty can take its pointer without exposing it
+let takeOpenPtyPointer: (pty: OpenPty) => Deno.PointerValue;
+
+/**
+ * A pty without a process, the process is started later with {@linkcode Pty}.
+ *
+ * ```ts
+ * const openPty = new OpenPty({ rows: 40, cols: 120, pixel_width: 0, pixel_height: 0 });
+ * const pty = new Pty({ cmd: "bash", args: [], env: [] }, { pty: openPty });
+ * ```
+ */
+export class OpenPty {
+  #this: Deno.PointerValue;
+
+  static {
+    takeOpenPtyPointer = (pty) => {
+      if (pty.#this === null) throw new Error("the pty was already used");
+      const ptr = pty.#this;
+      pty.#this = null;
+      return ptr;
+    };
+  }
+
+  /**
+   * Opens a new pty.
+   * @param size - The size of the pty, defaults to 24 rows and 80 cols.
+   */
+  constructor(size?: PtySize) {
+    const ptyBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_open(
+      size ? encodeJsonCstring(size) : null,
+      ptyBuf,
+    );
+    const ptr = createPtrFromBuffer(ptyBuf);
+    if (result === -1) throw decodeError(ptr);
+    this.#this = ptr;
+  }
+
+  /**
+   * Close the pty, only needed if no process was started in it.
+   */
+  close(): void {
+    if (this.#this === null) return;
+    LIBRARY.symbols.pty_open_close(this.#this);
+    this.#this = null;
+  }
+}
+
+/**
+ * Sets a callback that receives the errors and warnings of the pty background threads.
+ * Pass undefined to remove it.
+ * @param callback - The function called with the level and the message.
+ */
+export function setLogCallback(
+  callback: ((level: LogLevel, message: string) => void) | undefined,
+): void {
+  // the previous callback is left open, a background thread might still be calling it
+  if (callback === undefined) {
+    logCallback = undefined;
+    LIBRARY.symbols.pty_set_log_callback(null);
+    return;
+  }
+  logCallback = Deno.UnsafeCallback.threadSafe(
+    { parameters: ["i32", "pointer"], result: "void" },
+    (level, msgPtr) => {
+      if (msgPtr === null) return;
+      callback(level, new Deno.UnsafePointerView(msgPtr).getCString());
+    },
+  );
+  // don't keep the program alive just for the callback
+  logCallback.unref();
+  LIBRARY.symbols.pty_set_log_callback(logCallback.pointer);
+}
+
+/**
+ * Checks a command without starting it: the options, that cwd is a directory,
+ * the environment variable names and that the program can be found in the PATH the process would get.
+ * The process can still fail to start, for example if the program is removed in between.
+ * @param command - The command to check.
+ * @throws {PtyError} The error that creating a {@linkcode Pty} with the command would throw.
+ */
+export function validateCommand(command: Command): void {
+  const errBuf = new Uint8Array(8);
+  const result = LIBRARY.symbols.pty_validate_command(
+    encodeJsonCstring(command),
+    errBuf,
+  );
+  if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+}
+
+/** Adds the promise to `pending` until it settles */
+function trackPending<T>(
+  pending: Set<Promise<unknown>>,
+  promise: Promise<T>,
+): Promise<T> {
+  pending.add(promise);
+  const remove = () => pending.delete(promise);
+  promise.then(remove, remove);
+  return promise;
+}
+
+/**
+ * Runs `free` once the calls in `pending` settled, they use the pointer on another thread
+ * so freeing it before would be a use after free
+ */
+function afterPending(pending: Set<Promise<unknown>>, free: () => void): void {
+  if (pending.size === 0) {
+    free();
+    return;
+  }
+  Promise.allSettled(pending).then(free);
+}
+
+// set by PtyReader, so only Pty can create it
+let createPtyReader: (ptr: Deno.PointerValue) => PtyReader;
+
+/**
+ * Another reader of the output of a {@linkcode Pty}, created with {@linkcode Pty.cloneReader}.
+ *
+ * The readers share the output, each chunk is returned by only one of them, so they can't be used to
+ * get a copy of the output. The process exit is reported by the reader that receives it,
+ * the others report it when the pty is closed.
+ */
+export class PtyReader {
+  #this: Deno.PointerValue;
+  // the reads running on another thread, the reader is freed once they settle
+  #pending = new Set<Promise<unknown>>();
+
+  static {
+    createPtyReader = (ptr) => {
+      const reader = new PtyReader();
+      reader.#this = ptr;
+      return reader;
+    };
+  }
+
+  private constructor() {
+    this.#this = null;
+  }
+
+  #track<T>(promise: Promise<T>): Promise<T> {
+    return trackPending(this.#pending, promise);
+  }
+
+  /**
+   * Reads data from the pty.
+   * @returns A Promise that resolves to the data read from the pty.
+   */
+  async read(): Promise<{ data: string; done: boolean }> {
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_reader_read(this.#this, dataBuf),
+    );
+
+    if (result === 99) return { data: "", done: true };
+    if (result === 1) return { data: "", done: false };
+    const ptr = createPtrFromBuffer(dataBuf);
+
+    if (result === -1) throw decodeError(ptr);
+    return { data: decodeCstring(ptr), done: false };
+  }
+
+  /**
+   * Free the reader, it won't be usable after this call.
+   * The pty and the other readers keep working, it can be called before or after closing the pty.
+   * A read in flight keeps the reader alive until it returns.
+   */
+  close(): void {
+    const ptr = this.#this;
+    afterPending(this.#pending, () => LIBRARY.symbols.pty_reader_free(ptr));
+  }
+}
+
 /**
  * A class representing a Pty.
  */
 export class Pty {
   #this;
   #processExited = false;
+  #pid: number | undefined;
+  #exitCallback: Deno.UnsafeCallback | undefined;
+  // the calls running on another thread, the pty is freed once they settle
+  #pending = new Set<Promise<unknown>>();
 
   /**
    * Creates a new Pty instance with the specified command.
    * @param command - The command to be executed in the pty.
+   * @param options.pty - Start the command in a pty opened with {@linkcode OpenPty} instead of a new one,
+   * it can't be used again even if this fails.
    */
-  constructor(command: Command) {
+  constructor(command: Command, options?: { pty?: OpenPty }) {
     const pty_buf = new Uint8Array(8);
-    const result = LIBRARY.symbols.pty_create(
-      encodeJsonCstring(command),
-      pty_buf,
-    );
+    const pidBuf = new Uint32Array(1);
+    const result = options?.pty
+      ? LIBRARY.symbols.pty_spawn(
+        takeOpenPtyPointer(options.pty),
+        encodeJsonCstring(command),
+        pty_buf,
+        pidBuf,
+      )
+      : LIBRARY.symbols.pty_create(
+        encodeJsonCstring(command),
+        pty_buf,
+        pidBuf,
+      );
     const ptr = createPtrFromBuffer(pty_buf);
-    if (result === -1) throw new Error(decodeCstring(ptr));
+    if (result === -1) throw decodeError(ptr);
     this.#this = ptr;
+    // 0 means the pid couldn't be determined
+    this.#pid = pidBuf[0] || undefined;
   }
 
   /**
@@ -40,19 +242,382 @@ export class Pty {
   async read(): Promise<{ data: string; done: boolean }> {
     if (this.#processExited) return { data: "", done: true };
     const dataBuf = new Uint8Array(8);
-    const result = await LIBRARY.symbols.pty_read(this.#this, dataBuf);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read(this.#this, dataBuf),
+    );
+
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return { data: "", done: true };
+    }
+    /* No data yet */
+    if (result === 1) return { data: "", done: false };
+    const ptr = createPtrFromBuffer(dataBuf);
+
+    if (result === -1) throw decodeError(ptr);
+    // the last data, the next read reports the exit without calling the library
+    if (result === 98) this.#processExited = true;
+    return { data: decodeCstring(ptr), done: false };
+  }
+
+  /**
+   * Reads all the output that is buffered without waiting, the data is empty if nothing is buffered.
+   * Useful for a final sweep after the process exited.
+   * @returns The buffered data, `done` is true if the process exited after it.
+   */
+  drain(): { data: string; done: boolean } {
+    if (this.#processExited) return { data: "", done: true };
+    const dataBuf = new Uint8Array(8);
+    const endedBuf = new Uint8Array(1);
+    const result = LIBRARY.symbols.pty_drain(this.#this, dataBuf, endedBuf);
+    const ptr = createPtrFromBuffer(dataBuf);
+    if (result === -1) throw decodeError(ptr);
+    if (endedBuf[0] === 1) this.#processExited = true;
+    return { data: decodeCstring(ptr), done: endedBuf[0] === 1 };
+  }
+
+  /**
+   * Returns the output that is buffered without consuming it, the next read returns it.
+   * For example to check if a prompt is shown before reading.
+   * @returns The buffered data, `done` is true if the process exited after it.
+   */
+  peek(): { data: string; done: boolean } {
+    if (this.#processExited) return { data: "", done: true };
+    const dataBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_peek(this.#this, dataBuf);
+    const ptr = createPtrFromBuffer(dataBuf);
+    if (result === -1) throw decodeError(ptr);
+    return { data: decodeCstring(ptr), done: result === 99 };
+  }
+
+  /**
+   * Creates another reader of the output, it can be read concurrently with this pty.
+   * @returns The reader, it should be closed when it's not needed anymore.
+   */
+  cloneReader(): PtyReader {
+    const readerBuf = new Uint8Array(8);
+    LIBRARY.symbols.pty_clone_reader(this.#this, readerBuf);
+    return createPtyReader(createPtrFromBuffer(readerBuf));
+  }
+
+  /**
+   * Reads data from the stderr of the process, requires `separate_stderr`.
+   * @returns A Promise that resolves to the data read from the stderr, done is true once it's closed.
+   */
+  async readStderr(): Promise<{ data: string; done: boolean }> {
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_stderr(this.#this, dataBuf),
+    );
+
+    if (result === 99) return { data: "", done: true };
+    if (result === 1) return { data: "", done: false };
+    const ptr = createPtrFromBuffer(dataBuf);
+
+    if (result === -1) throw decodeError(ptr);
+    return { data: decodeCstring(ptr), done: false };
+  }
+
+  /**
+   * Reads data from the pty, also returns when the data was received.
+   * @returns A Promise that resolves to the data read from the pty, and the time it was received
+   * in milliseconds since the pty was created.
+   */
+  async readTimed(): Promise<{ data: string; done: boolean; time: number }> {
+    if (this.#processExited) return { data: "", done: true, time: 0 };
+    const dataBuf = new Uint8Array(8);
+    const tsBuf = new BigUint64Array(1);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_timed(this.#this, dataBuf, tsBuf),
+    );
+
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return { data: "", done: true, time: 0 };
+    }
+    if (result === 1) return { data: "", done: false, time: 0 };
+    const ptr = createPtrFromBuffer(dataBuf);
+
+    if (result === -1) throw decodeError(ptr);
+    return {
+      data: decodeCstring(ptr),
+      done: false,
+      time: Number(tsBuf[0]) / 1e6,
+    };
+  }
+
+  /**
+   * Interrupts a pending blocking read like {@linkcode Pty.readTimeout} or {@linkcode Pty.readUntil}, it throws
+   * a `PtyError` with the `Interrupted` kind. If no read is pending the next blocking read is interrupted.
+   * Only one read is interrupted per call.
+   */
+  interruptRead(): void {
+    LIBRARY.symbols.pty_interrupt_read(this.#this);
+  }
+
+  /**
+   * Reads data from the pty, waiting until data is available, the process exits
+   * or the timeout elapses.
+   * @param timeoutMs - The maximum time to wait in milliseconds.
+   * @returns A Promise that resolves to the data read from the pty, or undefined on timeout.
+   */
+  async readTimeout(
+    timeoutMs: number,
+  ): Promise<{ data: string; done: boolean } | undefined> {
+    if (this.#processExited) return { data: "", done: true };
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_timeout(this.#this, BigInt(timeoutMs), dataBuf),
+    );
 
     if (result === 99) {
       /* Process exited */
       this.#processExited = true;
       return { data: "", done: true };
     }
+    if (result === 1) return undefined;
     const ptr = createPtrFromBuffer(dataBuf);
 
-    if (result === -1) throw new Error(decodeCstring(ptr));
+    if (result === -1) throw decodeError(ptr);
+    // the last data, the next read reports the exit without calling the library
+    if (result === 98) this.#processExited = true;
     return { data: decodeCstring(ptr), done: false };
   }
 
+  /**
+   * Reads data from the pty into `buf`, avoids allocating a string for each read.
+   * The data that doesn't fit in `buf` is returned by the next call.
+   * The data is utf8 and can end in the middle of a character, use a `TextDecoder` with `stream: true` to decode it.
+   * NOTE: don't mix it with the other read methods, they don't return the data left by this one.
+   * @param buf - The buffer to write the data to.
+   * @returns A Promise that resolves to the number of bytes written, or null if the process exited.
+   */
+  async readInto(buf: Uint8Array): Promise<number | null> {
+    if (this.#processExited) return null;
+    const writtenBuf = new BigUint64Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_into(
+        this.#this,
+        buf,
+        BigInt(buf.length),
+        writtenBuf,
+        errBuf,
+      ),
+    );
+
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return null;
+    }
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+    return Number(writtenBuf[0]);
+  }
+
+  /**
+   * Reads until `needle` appears in the output, the process exits or the timeout elapses.
+   * @param needle - The text to wait for, for example a prompt.
+   * @param timeoutMs - The maximum time to wait in milliseconds.
+   * @returns A Promise that resolves to everything read so far, `found` is true if the needle was seen
+   * and `done` is true if the process exited.
+   * @throws {PtyError} On error, the data read so far is returned by the next read.
+   */
+  async readUntil(
+    needle: string,
+    timeoutMs: number,
+  ): Promise<{ data: string; found: boolean; done: boolean }> {
+    if (this.#processExited) return { data: "", found: false, done: true };
+    const needleBuf = ENCODER.encode(needle);
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_until(
+        this.#this,
+        needleBuf,
+        BigInt(needleBuf.length),
+        BigInt(timeoutMs),
+        dataBuf,
+      ),
+    );
+    const ptr = createPtrFromBuffer(dataBuf);
+    if (result === -1) throw decodeError(ptr);
+
+    const data = decodeCstring(ptr);
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return { data, found: false, done: true };
+    }
+    return { data, found: result === 0, done: false };
+  }
+
+  /**
+   * Same as {@linkcode Pty.readUntil} but waits for a match of a regex, for prompts that change like `user@host:~/dir$`.
+   * @param pattern - The regex, with the syntax of the rust regex crate (similar to JavaScript without lookarounds).
+   * It's matched against everything read so far, use `$` to match the end of the output.
+   * @param timeoutMs - The maximum time to wait in milliseconds.
+   * @returns A Promise that resolves to everything read so far, `found` is true if the regex matched
+   * and `done` is true if the process exited.
+   */
+  async readUntilRegex(
+    pattern: string,
+    timeoutMs: number,
+  ): Promise<{ data: string; found: boolean; done: boolean }> {
+    if (this.#processExited) return { data: "", found: false, done: true };
+    const patternBuf = ENCODER.encode(pattern);
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_read_until_regex(
+        this.#this,
+        patternBuf,
+        BigInt(patternBuf.length),
+        BigInt(timeoutMs),
+        dataBuf,
+      ),
+    );
+    const ptr = createPtrFromBuffer(dataBuf);
+    if (result === -1) throw decodeError(ptr);
+
+    const data = decodeCstring(ptr);
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return { data, found: false, done: true };
+    }
+    return { data, found: result === 0, done: false };
+  }
+
+  /**
+   * Writes the input, then reads until `until` appears in the output, the process exits or the timeout elapses.
+   * This is the "send a command and capture the answer" pattern of request/response protocols.
+   * @param input - The data to write, for example a command followed by a newline.
+   * @param until - The text that ends the answer, for example the next prompt.
+   * @param timeoutMs - The maximum time to wait in milliseconds.
+   * @returns A Promise that resolves to everything read so far, including the output that wasn't read
+   * before the write, `found` is true if `until` was seen and `done` is true if the process exited.
+   */
+  async exchange(
+    input: string,
+    until: string,
+    timeoutMs: number,
+  ): Promise<{ data: string; found: boolean; done: boolean }> {
+    if (this.#processExited) return { data: "", found: false, done: true };
+    const inputBuf = ENCODER.encode(input);
+    const untilBuf = ENCODER.encode(until);
+    const dataBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_exchange(
+        this.#this,
+        inputBuf,
+        BigInt(inputBuf.length),
+        untilBuf,
+        BigInt(untilBuf.length),
+        BigInt(timeoutMs),
+        dataBuf,
+      ),
+    );
+    const ptr = createPtrFromBuffer(dataBuf);
+    if (result === -1) throw decodeError(ptr);
+
+    const data = decodeCstring(ptr);
+    if (result === 99) {
+      /* Process exited */
+      this.#processExited = true;
+      return { data, found: false, done: true };
+    }
+    return { data, found: result === 0, done: false };
+  }
+
+  /**
+   * Reads all the output until the process exits, for commands that don't need interaction.
+   * @param timeoutMs - The maximum time to wait in milliseconds, waits forever if not set.
+   * @returns A Promise that resolves to the output and the exit code,
+   * the exit code is undefined on timeout and the data is the output read so far.
+   */
+  async collect(
+    timeoutMs?: number,
+  ): Promise<{ data: string; exitCode: number | undefined }> {
+    if (this.#processExited) return { data: "", exitCode: this.exitCode() };
+    const dataBuf = new Uint8Array(8);
+    const lenBuf = new BigUint64Array(1);
+    const codeBuf = new Int32Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_collect(
+        this.#this,
+        BigInt(timeoutMs ?? -1),
+        dataBuf,
+        lenBuf,
+        codeBuf,
+        errBuf,
+      ),
+    );
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+
+    const len = Number(lenBuf[0]);
+    const ptr = createPtrFromBuffer(dataBuf);
+    const data = len === 0
+      ? ""
+      : DECODER.decode(Deno.UnsafePointerView.getArrayBuffer(ptr, len));
+    // the data is copied by decode
+    LIBRARY.symbols.pty_free_bytes(ptr, BigInt(len));
+    if (result === 1) return { data, exitCode: undefined };
+    this.#processExited = true;
+    return { data, exitCode: codeBuf[0] };
+  }
+
+  /**
+   * Gets all the output of the process so far, including the output that was already read.
+   * Requires `record`.
+   * @returns The raw output, use a `TextDecoder` to decode it.
+   */
+  transcript(): Uint8Array {
+    const dataBuf = new Uint8Array(8);
+    const lenBuf = new BigUint64Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_transcript(
+      this.#this,
+      dataBuf,
+      lenBuf,
+      errBuf,
+    );
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+
+    const len = Number(lenBuf[0]);
+    const ptr = createPtrFromBuffer(dataBuf);
+    const data = len === 0
+      ? new Uint8Array()
+      : new Uint8Array(Deno.UnsafePointerView.getArrayBuffer(ptr, len)).slice();
+    LIBRARY.symbols.pty_free_bytes(ptr, BigInt(len));
+    return data;
+  }
+
+  /**
+   * Gets the number of bytes of output that are buffered and not read yet.
+   * Can be used to detect that the consumer is falling behind.
+   */
+  pendingBytes(): number {
+    const bytesBuf = new BigUint64Array(1);
+    LIBRARY.symbols.pty_pending_bytes(this.#this, bytesBuf);
+    return Number(bytesBuf[0]);
+  }
+
+  /**
+   * Gets the number of bytes read from the process and written to it since the pty was created.
+   * The read bytes include the separated stderr and the output that wasn't consumed yet.
+   */
+  stats(): { readBytes: number; writtenBytes: number } {
+    const readBuf = new BigUint64Array(1);
+    const writtenBuf = new BigUint64Array(1);
+    LIBRARY.symbols.pty_stats(this.#this, readBuf, writtenBuf);
+    return {
+      readBytes: Number(readBuf[0]),
+      writtenBytes: Number(writtenBuf[0]),
+    };
+  }
+
   /**
    * Writes data to the pty.
    * @param data - The data to write to the pty.
@@ -61,13 +626,220 @@ export class Pty {
     // NOTE: maybe we should tell the user that the process exited
     if (this.#processExited) return;
     const errBuf = new Uint8Array(8);
-    const result = await LIBRARY.symbols.pty_write(
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write(this.#this, encodeCstring(data), errBuf),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Writes data to the pty without waiting when the write queue is full, for doing your own flow control.
+   * The queue is only full when `write_channel_capacity` is set.
+   * @param data - The data to write to the pty.
+   * @returns true if the data was queued, false if the queue is full and nothing was queued.
+   */
+  tryWrite(data: string): boolean {
+    if (this.#processExited) return false;
+    const dataBuf = ENCODER.encode(data);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_try_write(
       this.#this,
-      encodeCstring(data),
+      dataBuf,
+      BigInt(dataBuf.length),
       errBuf,
     );
     if (result === -1) {
-      throw new Error(decodeCstring(createPtrFromBuffer(errBuf)));
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+    return result === 0;
+  }
+
+  /**
+   * Writes raw bytes to the pty, for input that isn't valid UTF-8.
+   * The writes are all or nothing for now, the count is returned so callers streaming a big buffer
+   * keep working if partial writes are added.
+   * @param data - The bytes to write to the pty.
+   * @returns A Promise that resolves to the number of bytes queued.
+   */
+  async writeBytes(data: Uint8Array): Promise<number> {
+    if (this.#processExited) return 0;
+    const writtenBuf = new BigUint64Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write_bytes(
+        this.#this,
+        data,
+        BigInt(data.length),
+        writtenBuf,
+        errBuf,
+      ),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+    return Number(writtenBuf[0]);
+  }
+
+  /**
+   * Writes the contents of a file to the pty, for big inputs like a script fed to an interpreter.
+   * The file is read by the library, so its contents don't need to be loaded in JavaScript.
+   * Use {@linkcode Pty.flush} to wait until it's written, a read error is thrown by the next read.
+   * @param path - The path of the file.
+   */
+  async writeFile(path: string): Promise<void> {
+    if (this.#processExited) return;
+    const pathBuf = ENCODER.encode(path);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write_file(
+        this.#this,
+        pathBuf,
+        BigInt(pathBuf.length),
+        errBuf,
+      ),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Writes data followed by a line ending to the pty, like pressing enter after typing it.
+   * The line ending is `\n`, or `\r` with the `line_ending` option of the command.
+   * @param data - The line to write, without the line ending.
+   */
+  async writeLine(data: string): Promise<void> {
+    if (this.#processExited) return;
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write_line(this.#this, encodeCstring(data), errBuf),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Writes data wrapped in the bracketed paste sequences (`\x1b[200~` and `\x1b[201~`), so programs
+   * that enabled bracketed paste handle it as pasted text, for example editors don't auto-indent each line.
+   * The end sequence is removed from the data so the paste can't end early.
+   * @param data - The text to paste.
+   */
+  async writePaste(data: string): Promise<void> {
+    if (this.#processExited) return;
+    const dataBuf = ENCODER.encode(data);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write_paste(
+        this.#this,
+        dataBuf,
+        BigInt(dataBuf.length),
+        errBuf,
+      ),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Writes data to the pty and waits until it's written or the timeout elapses.
+   * On timeout the data stays queued and is written when the process reads its input.
+   * @param data - The data to write to the pty.
+   * @param timeoutMs - The maximum time to wait in milliseconds.
+   * @returns A Promise that resolves to true if the data was written, or false on timeout.
+   */
+  async writeTimeout(data: string, timeoutMs: number): Promise<boolean> {
+    if (this.#processExited) return false;
+    const dataBuf = ENCODER.encode(data);
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_write_timeout(
+        this.#this,
+        dataBuf,
+        BigInt(dataBuf.length),
+        BigInt(timeoutMs),
+        errBuf,
+      ),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+    return result === 0;
+  }
+
+  /**
+   * Gets the number of writes that are queued and not written to the pty yet.
+   * Can be used with {@linkcode Pty.flush} to limit how much input is queued.
+   */
+  pendingWrites(): number {
+    const countBuf = new BigUint64Array(1);
+    LIBRARY.symbols.pty_pending_writes(this.#this, countBuf);
+    return Number(countBuf[0]);
+  }
+
+  /**
+   * Sends a control character, for example `sendControl("C")` sends Ctrl-C.
+   * @param key - The key pressed with Ctrl, a letter or one of `@[\]^_?`.
+   * @throws {PtyError} With the `InvalidInput` kind if the key doesn't have a control character.
+   */
+  sendControl(key: string): void {
+    // a longer string or another character would be truncated to a different key
+    if (!/^[@-_a-z?]$/.test(key)) {
+      throw new PtyError(
+        ErrorKind.InvalidInput,
+        `${JSON.stringify(key)} doesn't have a control character`,
+      );
+    }
+    if (this.#processExited) return;
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_send_control(
+      this.#this,
+      key.codePointAt(0)!,
+      errBuf,
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Waits until all the previous writes are written to the pty.
+   */
+  async flush(): Promise<void> {
+    if (this.#processExited) return;
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_flush(this.#this, errBuf),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Closes the input of the process (sends EOF), the output can still be read.
+   * Writing after this call throws until `reopenStdin` is called.
+   */
+  closeStdin(): void {
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_close_stdin(this.#this, errBuf);
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Allows writing again after `closeStdin`, for programs that keep reading after an EOF.
+   * Not supported on windows, where closing stdin closes the input pipe.
+   */
+  reopenStdin(): void {
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_reopen_stdin(this.#this, errBuf);
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
     }
   }
 
@@ -76,35 +848,305 @@ export class Pty {
    * @returns The size of the pty.
    */
   getSize(): PtySize {
+    const sizeBuf = new Uint16Array(4);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_get_size_raw(
+      this.#this,
+      sizeBuf.subarray(0, 1),
+      sizeBuf.subarray(1, 2),
+      sizeBuf.subarray(2, 3),
+      sizeBuf.subarray(3, 4),
+      errBuf,
+    );
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+    return {
+      rows: sizeBuf[0],
+      cols: sizeBuf[1],
+      pixel_width: sizeBuf[2],
+      pixel_height: sizeBuf[3],
+    };
+  }
+
+  /**
+   * Switches the terminal between raw mode (no line buffering, no echo, no signals from control characters)
+   * and cooked mode.
+   * Not supported on windows.
+   * @param raw - true for raw mode, false for cooked mode.
+   */
+  setRaw(raw: boolean): void {
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_set_raw(this.#this, raw, errBuf);
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Gets the path of the terminal device, for example `/dev/pts/4`.
+   * Not supported on windows.
+   * @returns The path of the terminal device.
+   */
+  ttyName(): string {
     const dataBuf = new Uint8Array(8);
-    const result = LIBRARY.symbols.pty_get_size(this.#this, dataBuf);
+    const result = LIBRARY.symbols.pty_ttyname(this.#this, dataBuf);
     const ptr = createPtrFromBuffer(dataBuf);
-    if (result === -1) throw new Error(decodeCstring(ptr));
-    return decodeJsonCstring(ptr);
+    if (result === -1) throw decodeError(ptr);
+    return decodeCstring(ptr);
+  }
+
+  /**
+   * Gets a fd that is readable while `read` has something to return, so the pty can be registered
+   * in an event loop instead of polling. On windows it's an event handle that is signaled instead.
+   * The fd is owned by the pty and closed by `close`.
+   * @returns The fd.
+   */
+  readableFd(): number {
+    const fdBuf = new Int32Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_readable_fd(this.#this, fdBuf, errBuf);
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+    return fdBuf[0];
+  }
+
+  /**
+   * Opens a new fd to the slave side of the pty, for example to pass it to another process.
+   * The fd is owned by the caller and the output doesn't end while it's open.
+   * Not supported on windows, it throws a `PtyError` with `ErrorKind.Unsupported`.
+   * @returns The fd.
+   */
+  slaveFd(): number {
+    const fdBuf = new Int32Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_slave_fd(this.#this, fdBuf, errBuf);
+    if (result === -1) throw decodeError(createPtrFromBuffer(errBuf));
+    return fdBuf[0];
   }
 
   /**
    * Resizes the pty to the specified size.
-   * @param size - The new size for the pty.
+   * @param size - The new size for the pty, the pixel size defaults to the current one.
+   * @param options.verify - Read the size back and throw if it wasn't applied. On unix SIGWINCH is also
+   * sent again to the foreground process, for programs that missed the first one and don't redraw.
+   */
+  resize(
+    size: Pick<PtySize, "rows" | "cols"> & Partial<PtySize>,
+    options?: { verify?: boolean },
+  ): void {
+    let { pixel_width, pixel_height } = size;
+    if (pixel_width === undefined || pixel_height === undefined) {
+      const current = this.getSize();
+      pixel_width ??= current.pixel_width;
+      pixel_height ??= current.pixel_height;
+    }
+    const errBuf = new Uint8Array(8);
+    const resize = options?.verify
+      ? LIBRARY.symbols.pty_resize_verified
+      : LIBRARY.symbols.pty_resize_raw;
+    const result = resize(
+      this.#this,
+      size.rows,
+      size.cols,
+      pixel_width,
+      pixel_height,
+      errBuf,
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Sends a signal to the process.
+   *
+   * On unix the signal is sent to the foreground process group of the pty.
+   * On windows only SIGINT (2), SIGKILL (9) and SIGTERM (15) are supported.
+   * @param signal - The signal number, for example 2 for SIGINT.
    */
-  resize(size: PtySize): void {
+  signal(signal: number): void {
     const errBuf = new Uint8Array(8);
-    const result = LIBRARY.symbols.pty_resize(
+    const result = LIBRARY.symbols.pty_signal(this.#this, signal, errBuf);
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Sends a signal to the process group of the spawned process, which includes its children
+   * unless they moved to another group, for example background jobs of an interactive shell.
+   *
+   * On windows this is the same as `signal`.
+   * @param signal - The signal number, for example 9 for SIGKILL.
+   */
+  signalGroup(signal: number): void {
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_signal_group(
       this.#this,
-      encodeJsonCstring(size),
+      signal,
       errBuf,
     );
     if (result === -1) {
-      throw new Error(decodeCstring(createPtrFromBuffer(errBuf)));
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+  }
+
+  /**
+   * Asks the process to exit with SIGTERM, and kills it if it's still running after the grace period.
+   * @param graceMs - How long to wait for the process to exit before killing it, in milliseconds.
+   */
+  async terminate(graceMs: number): Promise<void> {
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_terminate(this.#this, BigInt(graceMs), errBuf),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
     }
   }
 
+  /**
+   * Waits for the process to exit.
+   * @param timeoutMs - The maximum time to wait in milliseconds, waits forever if not set.
+   * @returns A Promise that resolves to true if the process exited, or false on timeout.
+   */
+  async wait(timeoutMs?: number): Promise<boolean> {
+    const errBuf = new Uint8Array(8);
+    const result = await this.#track(
+      LIBRARY.symbols.pty_wait(this.#this, BigInt(timeoutMs ?? -1), errBuf),
+    );
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+    return result === 0;
+  }
+
+  /**
+   * Checks if the process is still running, without consuming any output.
+   * @returns true if the process is running.
+   */
+  isAlive(): boolean {
+    const aliveBuf = new Uint8Array(1);
+    LIBRARY.symbols.pty_is_alive(this.#this, aliveBuf);
+    return aliveBuf[0] === 1;
+  }
+
+  /**
+   * Checks if the process exited and all its output was queued, without finishing any reader.
+   * The readers may still have data to read.
+   * @returns true if the output has ended.
+   */
+  hasEnded(): boolean {
+    const endedBuf = new Uint8Array(1);
+    LIBRARY.symbols.pty_has_ended(this.#this, endedBuf);
+    return endedBuf[0] === 1;
+  }
+
+  /**
+   * Gets the pid of the process.
+   * @returns The pid, or undefined if it couldn't be determined.
+   */
+  pid(): number | undefined {
+    return this.#pid;
+  }
+
+  /**
+   * Gets the exit code of the process.
+   * @returns The exit code, or undefined if the process is still running.
+   */
+  exitCode(): number | undefined {
+    const codeBuf = new Int32Array(1);
+    const errBuf = new Uint8Array(8);
+    const result = LIBRARY.symbols.pty_exit_code(this.#this, codeBuf, errBuf);
+    if (result === -1) {
+      throw decodeError(createPtrFromBuffer(errBuf));
+    }
+    if (result === 1) return undefined;
+    return codeBuf[0];
+  }
+
+  /**
+   * Gets the signal that terminated the process, only available on unix.
+   * @returns The signal number, or undefined if the process is still running or wasn't terminated by a signal.
+   */
+  exitSignal(): number | undefined {
+    const signalBuf = new Int32Array(1);
+    const result = LIBRARY.symbols.pty_exit_signal(this.#this, signalBuf);
+    if (result === 1) return undefined;
+    return signalBuf[0];
+  }
+
+  /**
+   * Sets a callback that is called once the process exits, it's called right away if the process already exited.
+   * The program is kept alive until then. Pass undefined to remove it.
+   * @param callback - The function called with the pid and the exit code, the code is -1 if it's unknown.
+   */
+  onExit(callback: ((pid: number, code: number) => void) | undefined): void {
+    // the previous callback is left open, the waiter thread might still be calling it
+    this.#exitCallback?.unref();
+    if (callback === undefined) {
+      this.#exitCallback = undefined;
+      LIBRARY.symbols.pty_set_exit_callback(this.#this, null);
+      return;
+    }
+    // keeps the program alive until the process exits
+    const exitCallback = Deno.UnsafeCallback.threadSafe(
+      { parameters: ["u32", "i32"], result: "void" },
+      (pid, code) => {
+        exitCallback.unref();
+        callback(pid, code);
+      },
+    );
+    this.#exitCallback = exitCallback;
+    LIBRARY.symbols.pty_set_exit_callback(this.#this, exitCallback.pointer);
+  }
+
   /**
     Close the Pty, the pty won't be usable after this call
-    NOTE: the process isn't killed in windows (https://github.com/sigmaSd/deno-pty-ffi/issues/4)
+    Queued writes that didn't reach the process yet are lost, use `closeFlush` to write them first
+    The process is killed with its children, on windows the children that started before the process
+    was assigned to a job are not killed. Set `close_signal` when creating the pty to send another signal.
+    The calls still in flight, like `wait` or `readTimeout`, are interrupted or return once the process is
+    killed, and the pty is freed after they return. Without kill, they keep the pty alive until they return.
+    @param options.kill - Kill the process, defaults to true. Set it to false to leave the process running.
   */
-  close(): void {
+  close(options?: { kill?: boolean }): void {
     this.#processExited = true;
-    LIBRARY.symbols.pty_close(this.#this);
+    const ptr = this.#this;
+    const kill = options?.kill ?? true;
+    if (this.#pending.size === 0) {
+      if (kill) LIBRARY.symbols.pty_close(ptr);
+      else LIBRARY.symbols.pty_close_nokill(ptr);
+      return;
+    }
+    // the calls in flight use the pty on another thread, make them return before freeing it
+    LIBRARY.symbols.pty_cancel(ptr, kill ? 1 : 0);
+    afterPending(this.#pending, () => LIBRARY.symbols.pty_close_nokill(ptr));
+  }
+
+  /**
+   * Same as {@linkcode Pty.close} but the queued writes are written to the process first,
+   * so a write followed by a close doesn't lose the input.
+   * @param options.kill - Kill the process, defaults to true.
+   * @param options.timeoutMs - How long to wait for the process to read its input before closing anyway,
+   * defaults to 1000.
+   */
+  async closeFlush(
+    options?: { kill?: boolean; timeoutMs?: number },
+  ): Promise<void> {
+    if (!this.#processExited) {
+      const errBuf = new Uint8Array(8);
+      // the pty is closed anyway, on timeout or if the input is already closed
+      await this.#track(
+        LIBRARY.symbols.pty_flush_timeout(
+          this.#this,
+          BigInt(options?.timeoutMs ?? 1000),
+          errBuf,
+        ),
+      );
+    }
+    this.close({ kill: options?.kill });
+  }
+
+  #track<T>(promise: Promise<T>): Promise<T> {
+    return trackPending(this.#pending, promise);
   }
 }
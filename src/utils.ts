@@ -1,3 +1,5 @@
+import { type ErrorKind, PtyError } from "./error.ts";
+
 const ENCODER = new TextEncoder();
 
 export function encodeJsonCstring<T>(data: T): Uint8Array {
@@ -25,6 +27,15 @@ export function decodeCstring(
   return new Deno.UnsafePointerView(ptr).getCString();
 }
 
+export function decodeError(
+  ptr: NonNullable<Deno.PointerValue>,
+): PtyError {
+  // ptr is a cstring encoding { kind, message }
+  const { kind, message }: { kind: ErrorKind; message: string } =
+    decodeJsonCstring(ptr);
+  return new PtyError(kind, message);
+}
+
 export function createPtrFromBuffer(buffer: Uint8Array) {
   const ptr = Deno.UnsafePointer.create(
     //TODO
@@ -1,5 +1,10 @@
-import { assert, assertEquals } from "jsr:@std/assert@0.220.1";
-import { Pty } from "../mod.ts";
+import {
+  assert,
+  assertEquals,
+  assertRejects,
+  assertThrows,
+} from "jsr:@std/assert@0.220.1";
+import { ErrorKind, OpenPty, Pty, PtyError, validateCommand } from "../mod.ts";
 
 Deno.test("smoke", async () => {
   const jobs = [];
@@ -53,9 +58,209 @@ Deno.test("getSize/resize", () => {
     pixel_width: 1,
   });
 
+  // the pixel size is kept
+  pty.resize({ rows: 40, cols: 100 });
+  assertEquals(pty.getSize(), {
+    rows: 40,
+    cols: 100,
+    pixel_height: 1,
+    pixel_width: 1,
+  });
+
+  pty.close();
+});
+
+Deno.test({
+  name: "tty name",
+  ignore: Deno.build.os === "windows",
+  fn: () => {
+    const pty = new Pty({
+      cmd: "deno",
+      args: ["repl"],
+      env: [["NO_COLOR", "1"]],
+    });
+
+    assert(pty.ttyName().startsWith("/dev/"));
+
+    pty.close();
+  },
+});
+
+Deno.test("readable fd", () => {
+  const pty = new Pty({ cmd: "echo", args: ["hello"], env: [] });
+
+  const fd = pty.readableFd();
+  assert(Number.isInteger(fd) && fd >= 0);
+  // the same fd for the lifetime of the pty
+  assertEquals(pty.readableFd(), fd);
+
   pty.close();
 });
 
+Deno.test("slave fd", () => {
+  const pty = new Pty({ cmd: "sleep", args: ["10"], env: [] });
+
+  if (Deno.build.os === "windows") {
+    const err = assertThrows(() => pty.slaveFd(), PtyError);
+    assertEquals(err.kind, ErrorKind.Unsupported);
+  } else {
+    // the fd stays open until the test process exits
+    assert(pty.slaveFd() > 2);
+  }
+
+  pty.close();
+});
+
+Deno.test({
+  name: "echo disabled",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "cat",
+      args: [],
+      env: [],
+      echo: false,
+    });
+
+    await pty.write("secret\n");
+    pty.closeStdin();
+
+    let output = "";
+    while (true) {
+      const r = await pty.readTimeout(5000);
+      assert(r !== undefined);
+      if (r.done) break;
+      output += r.data;
+    }
+    // cat prints it once, the terminal doesn't echo it
+    assertEquals(output.split("secret").length - 1, 1);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "separate stderr",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "echo out; echo err >&2"],
+      env: [],
+      separate_stderr: true,
+    });
+
+    let stderr = "";
+    while (true) {
+      const { data, done } = await pty.readStderr();
+      if (done) break;
+      stderr += data;
+      await new Promise((r) => setTimeout(r, 10));
+    }
+    assertEquals(stderr, "err\n");
+
+    let output = "";
+    while (true) {
+      const r = await pty.readTimeout(5000);
+      assert(r !== undefined);
+      if (r.done) break;
+      output += r.data;
+    }
+    assert(output.includes("out"));
+    assert(!output.includes("err"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "umask",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "umask"],
+      env: [],
+      umask: 0o027,
+    });
+
+    let output = "";
+    while (true) {
+      const r = await pty.readTimeout(5000);
+      assert(r !== undefined);
+      if (r.done) break;
+      output += r.data;
+    }
+    assert(output.includes("0027"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "newline translation disabled",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "printf",
+      args: ["a\nb\n"],
+      env: [],
+      onlcr: false,
+    });
+
+    const { data } = await pty.collect(5000);
+    assertEquals(data, "a\nb\n");
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "verified resize",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd:
+        "trap 'echo winch' WINCH; echo ready; while true; do sleep 0.1; done",
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    assert((await pty.readUntil("ready", 5000)).found);
+    const size = { rows: 40, cols: 100, pixel_width: 0, pixel_height: 0 };
+    pty.resize(size, { verify: true });
+    assertEquals(pty.getSize(), size);
+    assert((await pty.readUntil("winch", 5000)).found);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "initial size",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "stty",
+      args: ["size"],
+      env: [],
+      size: { rows: 50, cols: 120, pixel_width: 0, pixel_height: 0 },
+    });
+
+    let output = "";
+    while (true) {
+      const r = await pty.readTimeout(5000);
+      assert(r !== undefined);
+      if (r.done) break;
+      output += r.data;
+    }
+    assert(output.includes("50 120"));
+
+    pty.close();
+  },
+});
+
 Deno.test("with cwd set", async () => {
   const tmpDir = await Deno.makeTempDir();
   const pty = new Pty({
@@ -74,6 +279,1300 @@ Deno.test("with cwd set", async () => {
   pty.close();
 });
 
+Deno.test("exit code", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "Deno.exit(3)"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  while (true) {
+    const { done } = await pty.read();
+    if (done) break;
+    await new Promise((r) => setTimeout(r, 100));
+  }
+  assertEquals(pty.exitCode(), 3);
+
+  pty.close();
+});
+
+Deno.test({
+  name: "shell",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "echo hello | tr a-z A-Z",
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    let output = "";
+    while (true) {
+      const r = await pty.readTimeout(5000);
+      assert(r !== undefined);
+      if (r.done) break;
+      output += r.data;
+    }
+    assert(output.includes("HELLO"));
+
+    pty.close();
+  },
+});
+
+Deno.test("pending bytes", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  await pty.wait();
+  // give the reader thread time to read the output
+  await new Promise((r) => setTimeout(r, 100));
+  assert(pty.pendingBytes() > 0);
+  while (!(await pty.read()).done);
+  assertEquals(pty.pendingBytes(), 0);
+
+  pty.close();
+});
+
+Deno.test("structured errors", () => {
+  assertThrows(
+    () =>
+      new Pty({
+        cmd: "this-command-does-not-exist",
+        args: [],
+        env: [],
+      }),
+    PtyError,
+  );
+});
+
+Deno.test("flush", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // read header
+  await pty.read();
+
+  await pty.write("5+4\n\r");
+  await pty.flush();
+  assertEquals(pty.pendingWrites(), 0);
+  assert(await pty.writeTimeout("1+1\n\r", 5000));
+  await write_and_expect(pty, "let a = 4; a + a\n\r", "8");
+
+  pty.close();
+});
+
+Deno.test("terminate", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // read header
+  await pty.read();
+
+  await pty.terminate(1000);
+  assert(!pty.isAlive());
+  // SIGTERM
+  if (Deno.build.os !== "windows") assertEquals(pty.exitSignal(), 15);
+
+  pty.close();
+});
+
+Deno.test("close stdin", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // read header
+  await pty.read();
+
+  // the repl exits on EOF
+  pty.closeStdin();
+  assertEquals(await pty.wait(5000), true);
+  await assertRejects(() => pty.write("5+4\n\r"), PtyError);
+
+  pty.close();
+});
+
+Deno.test({
+  name: "reopen stdin",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd:
+        'while true; do if read line; then echo "line $line"; else echo eof; fi; done',
+      args: [],
+      env: [],
+      shell: true,
+      echo: false,
+    });
+
+    pty.closeStdin();
+    assert((await pty.readUntil("eof", 5000)).found);
+    await assertRejects(() => pty.write("a\n"), PtyError);
+
+    pty.reopenStdin();
+    await pty.writeLine("b");
+    assert((await pty.readUntil("line b", 5000)).found);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "large write",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "wc -c",
+      args: [],
+      env: [],
+      shell: true,
+      echo: false,
+    });
+
+    // 1MB in lines, the terminal drops the input of lines longer than 4096 bytes
+    await pty.write("123456789\n".repeat(100_000));
+    pty.closeStdin();
+    // closing stdin sends a newline before EOF
+    const { data } = await pty.collect(10000);
+    assert(data.includes("1000001"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "stats",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "read line; echo done",
+      args: [],
+      env: [],
+      shell: true,
+      echo: false,
+    });
+
+    await pty.writeLine("hello");
+    await pty.flush();
+    const { data } = await pty.collect(5000);
+    const { readBytes, writtenBytes } = pty.stats();
+    assertEquals(writtenBytes, 6);
+    assertEquals(readBytes, new TextEncoder().encode(data).length);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "bracketed paste",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "cat -v",
+      args: [],
+      env: [],
+      shell: true,
+      echo: false,
+    });
+
+    await pty.writePaste("a\x1b[201~b");
+    pty.closeStdin();
+    const { data } = await pty.collect(5000);
+    assert(data.includes("^[[200~ab^[[201~"));
+
+    pty.close();
+  },
+});
+
+Deno.test("send control", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // read header
+  await pty.read();
+
+  // would be truncated to "C"
+  const err = assertThrows(() => pty.sendControl("\u0143"), PtyError);
+  assertEquals(err.kind, ErrorKind.InvalidInput);
+  assertThrows(() => pty.sendControl("cd"), PtyError);
+
+  // Ctrl-D exits the repl
+  pty.sendControl("d");
+  assertEquals(await pty.wait(5000), true);
+
+  pty.close();
+});
+
+Deno.test("read timed", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: [
+      "eval",
+      "console.log('a'); await new Promise((r) => setTimeout(r, 500)); console.log('b')",
+    ],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  const times: Record<string, number> = {};
+  while (true) {
+    const { data, done, time } = await pty.readTimed();
+    if (done) break;
+    if (data.includes("a")) times.a ??= time;
+    if (data.includes("b")) times.b ??= time;
+    await new Promise((r) => setTimeout(r, 100));
+  }
+  assert(times.b - times.a >= 400);
+
+  pty.close();
+});
+
+Deno.test("read into", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello world')"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // small buffer so the output is split between reads
+  const buf = new Uint8Array(4);
+  const decoder = new TextDecoder();
+  let output = "";
+  while (true) {
+    const n = await pty.readInto(buf);
+    if (n === null) break;
+    output += decoder.decode(buf.subarray(0, n), { stream: true });
+  }
+  assert(output.includes("hello world"));
+
+  pty.close();
+});
+
+Deno.test("read until", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assert((await pty.readUntil("> ", 5000)).found);
+  await pty.write("5+4\n\r");
+  const { data, found } = await pty.readUntil("9", 5000);
+  assert(found);
+  assert(data.includes("9"));
+  assertEquals((await pty.readUntil("never printed", 100)).found, false);
+
+  pty.close();
+});
+
+Deno.test("read until regex", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assert((await pty.readUntilRegex(">\\s$", 5000)).found);
+  await pty.write("6*7\n\r");
+  const { data, found } = await pty.readUntilRegex("4\\d", 5000);
+  assert(found);
+  assert(data.includes("42"));
+  await assertRejects(() => pty.readUntilRegex("(", 100), PtyError);
+
+  pty.close();
+});
+
+Deno.test("exchange", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assert((await pty.readUntil("> ", 5000)).found);
+  const { data, found } = await pty.exchange("6*7\n\r", "42", 5000);
+  assert(found);
+  assert(data.includes("42"));
+
+  pty.close();
+});
+
+Deno.test({
+  name: "write line",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: 'read line; echo "got $line"',
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    await pty.writeLine("hello");
+    const { data } = await pty.collect(5000);
+    assert(data.includes("got hello"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "write bytes",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "stty raw -echo; head -c 3 | od -An -tx1",
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    // wait for stty so the bytes aren't handled by the line discipline
+    await new Promise((r) => setTimeout(r, 500));
+    const data = new Uint8Array([0xff, 0x00, 0x80]);
+    assertEquals(await pty.writeBytes(data), 3);
+    const { data: output } = await pty.collect(5000);
+    assert(output.includes("ff 00 80"));
+
+    pty.close();
+  },
+});
+
+Deno.test("interrupt read", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "await new Promise((r) => setTimeout(r, 10000))"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  const read = pty.readTimeout(10000);
+  await new Promise((r) => setTimeout(r, 100));
+  pty.interruptRead();
+  const err = await assertRejects(() => read, PtyError);
+  assertEquals(err.kind, ErrorKind.Interrupted);
+
+  pty.close();
+});
+
+Deno.test({
+  name: "try write",
+  ignore: Deno.build.os === "windows",
+  fn: () => {
+    // never reads its input, so the writes stay queued
+    const pty = new Pty({
+      cmd: "sleep",
+      args: ["10"],
+      env: [],
+      write_channel_capacity: 1,
+    });
+
+    const data = "a".repeat(64 * 1024);
+    let queued = 0;
+    while (pty.tryWrite(data)) queued++;
+    // one write is taken by the writer thread and one waits in the queue
+    assert(queued <= 2);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "write file",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const file = await Deno.makeTempFile();
+    await Deno.writeTextFile(file, "hello\nworld\n");
+    const pty = new Pty({
+      cmd: 'read a; read b; echo "got $a $b"',
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    const error = await assertRejects(
+      () => pty.writeFile(`${file}-missing`),
+      PtyError,
+    );
+    assertEquals(error.kind, ErrorKind.NotFound);
+
+    await pty.writeFile(file);
+    const { data } = await pty.collect(5000);
+    assert(data.includes("got hello world"));
+
+    pty.close();
+    await Deno.remove(file);
+  },
+});
+
+Deno.test("wait", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: [
+      "eval",
+      "await new Promise((r) => setTimeout(r, 1000)); Deno.exit(2)",
+    ],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assertEquals(await pty.wait(100), false);
+  assert(pty.isAlive());
+  assertEquals(await pty.wait(), true);
+  assert(!pty.isAlive());
+  assertEquals(pty.exitCode(), 2);
+
+  pty.close();
+});
+
+Deno.test({
+  name: "close flush",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const file = await Deno.makeTempFile();
+    const pty = new Pty({
+      cmd: `head -n 1 > ${file}`,
+      args: [],
+      env: [],
+      shell: true,
+    });
+
+    await pty.write("hello\n");
+    await pty.closeFlush({ kill: false });
+
+    let content = "";
+    for (let i = 0; i < 50 && !content; i++) {
+      await new Promise((r) => setTimeout(r, 100));
+      content = await Deno.readTextFile(file);
+    }
+    assertEquals(content, "hello\n");
+    await Deno.remove(file);
+  },
+});
+
+Deno.test("close with calls in flight", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "await new Promise((r) => setTimeout(r, 10000))"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  const read = pty.readUntil("never", 10000);
+  const wait = pty.wait();
+  // the pty is freed once they return, not while they use it
+  pty.close();
+  const [, waited] = await Promise.allSettled([read, wait]);
+  assertEquals(waited, { status: "fulfilled", value: true });
+});
+
+Deno.test("read with timeout", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "await new Promise((r) => setTimeout(r, 1000))"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // the process doesn't print anything
+  assertEquals(await pty.readTimeout(100), undefined);
+
+  while (true) {
+    const r = await pty.readTimeout(5000);
+    assert(r !== undefined);
+    if (r.done) break;
+  }
+
+  pty.close();
+});
+
+Deno.test("signal", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  // read header
+  await pty.read();
+
+  // SIGTERM
+  pty.signal(15);
+  while (true) {
+    const r = await pty.readTimeout(5000);
+    assert(r !== undefined);
+    if (r.done) break;
+  }
+
+  pty.close();
+});
+
+Deno.test({
+  name: "signal group",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "sleep 100 & echo started; wait"],
+      env: [],
+    });
+
+    assert((await pty.readUntil("started", 5000)).found);
+    // SIGKILL, the shell and the background sleep are in the same group
+    pty.signalGroup(9);
+    assert(await pty.wait(5000));
+    assertEquals(pty.exitSignal(), 9);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "start stopped",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "echo",
+      args: ["hello"],
+      env: [],
+      start_stopped: true,
+    });
+
+    assertEquals(await pty.wait(500), false);
+    assertEquals(pty.peek().data, "");
+    // SIGCONT
+    pty.signalGroup(Deno.build.os === "darwin" ? 19 : 18);
+    const { data, exitCode } = await pty.collect(5000);
+    assert(data.includes("hello"));
+    assertEquals(exitCode, 0);
+
+    pty.close();
+  },
+});
+
+Deno.test("inherit fds is unsupported", () => {
+  assertThrows(
+    () => new Pty({ cmd: "true", args: [], env: [], inherit_fds: [3] }),
+    PtyError,
+    "inherit_fds is not supported",
+  );
+});
+
+Deno.test("arg0 is unsupported", () => {
+  assertThrows(
+    () => new Pty({ cmd: "bash", args: [], env: [], arg0: "-bash" }),
+    PtyError,
+    "arg0 is not supported",
+  );
+});
+
+Deno.test({
+  name: "controlling terminal can't be disabled",
+  ignore: Deno.build.os === "windows",
+  fn: () => {
+    assertThrows(
+      () =>
+        new Pty({
+          cmd: "true",
+          args: [],
+          env: [],
+          set_controlling_terminal: false,
+        }),
+      PtyError,
+      "set_controlling_terminal can't be disabled",
+    );
+  },
+});
+
+Deno.test({
+  name: "detach",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sleep",
+      args: ["30"],
+      env: [],
+      detach: true,
+    });
+
+    const { data, exitCode } = await pty.collect(5000);
+    assertEquals(exitCode, 0);
+    const pid = parseInt(data);
+    pty.close();
+
+    // still running after the pty is closed
+    await new Promise((r) => setTimeout(r, 100));
+    Deno.kill(pid, "SIGCONT");
+    Deno.kill(pid, "SIGKILL");
+  },
+});
+
+Deno.test({
+  name: "split utf8 character",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    // the euro sign is 3 bytes, the last one is written separately
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "printf 'a\\342\\202'; sleep 0.5; printf '\\254b'"],
+      env: [],
+    });
+
+    let output = "";
+    while (true) {
+      const { data, done } = await pty.read();
+      output += data;
+      if (done) break;
+    }
+    assertEquals(output, "a€b");
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "hex decode",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "printf",
+      args: ["a\\377b"],
+      env: [],
+      decode: "hex",
+    });
+
+    const { data } = await pty.collect(5000);
+    assert(data.includes("a\\xffb"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "timeout",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sleep",
+      args: ["10"],
+      env: [],
+      timeout_ms: 200,
+    });
+
+    const error = await assertRejects(
+      async () => {
+        while (true) {
+          const r = await pty.readTimeout(5000);
+          assert(r !== undefined);
+          if (r.done) break;
+        }
+      },
+      PtyError,
+      "timed out",
+    );
+    assertEquals(error.kind, ErrorKind.TimedOut);
+    assert((await pty.readTimeout(5000))?.done);
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "idle timeout",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "echo start; sleep 0.2; echo middle; sleep 10"],
+      env: [],
+      idle_timeout_ms: 500,
+    });
+
+    let output = "";
+    const error = await assertRejects(
+      async () => {
+        while (true) {
+          const r = await pty.readTimeout(5000);
+          assert(r !== undefined);
+          if (r.done) break;
+          output += r.data;
+        }
+      },
+      PtyError,
+      "didn't output anything",
+    );
+    assertEquals(error.kind, ErrorKind.TimedOut);
+    // the output resets the timer
+    assert(output.includes("middle"));
+
+    pty.close();
+  },
+});
+
+Deno.test({
+  name: "close kills the children",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      cmd: "sh",
+      args: ["-c", "sleep 100 & echo child $!; wait"],
+      env: [],
+    });
+
+    const { data, found } = await pty.readUntil("\n", 5000);
+    assert(found);
+    const child = Number(data.match(/child (\d+)/)?.[1]);
+    assert(child > 0);
+    pty.close();
+
+    // wait until the child is gone
+    let alive = true;
+    for (let i = 0; i < 50 && alive; i++) {
+      try {
+        Deno.kill(child, "SIGCONT");
+        await new Promise((r) => setTimeout(r, 100));
+      } catch {
+        alive = false;
+      }
+    }
+    assert(!alive);
+  },
+});
+
+Deno.test({
+  name: "close signal",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const file = await Deno.makeTempFile();
+    const pty = new Pty({
+      cmd: "sh",
+      args: [
+        "-c",
+        `trap 'echo bye > ${file}; exit' TERM HUP; echo ready; while true; do sleep 0.1; done`,
+      ],
+      env: [],
+      // SIGTERM
+      close_signal: 15,
+    });
+
+    assert((await pty.readUntil("ready", 5000)).found);
+    pty.close();
+
+    // the process handles the signal instead of being killed
+    let content = "";
+    for (let i = 0; i < 50 && !content; i++) {
+      await new Promise((r) => setTimeout(r, 100));
+      content = await Deno.readTextFile(file);
+    }
+    assertEquals(content, "bye\n");
+    await Deno.remove(file);
+  },
+});
+
+Deno.test("collect", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello'); Deno.exit(3)"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  const { data, exitCode } = await pty.collect(5000);
+  assert(data.includes("hello"));
+  assertEquals(exitCode, 3);
+
+  pty.close();
+});
+
+Deno.test("open then spawn", async () => {
+  const openPty = new OpenPty({
+    rows: 40,
+    cols: 120,
+    pixel_width: 0,
+    pixel_height: 0,
+  });
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  }, { pty: openPty });
+
+  assertEquals(pty.getSize().rows, 40);
+  assert((await pty.readUntil("> ", 5000)).found);
+  // the opened pty can't be used twice
+  assertThrows(() =>
+    new Pty({ cmd: "deno", args: [], env: [] }, { pty: openPty })
+  );
+
+  pty.close();
+});
+
+Deno.test("clone reader", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["repl"],
+    env: [["NO_COLOR", "1"]],
+  });
+  const reader = pty.cloneReader();
+
+  await pty.write("5+4\n\r");
+  // the output is returned by one of the readers
+  let output = "";
+  for (let i = 0; i < 50 && !output.includes("9"); i++) {
+    output += (await reader.read()).data + (await pty.read()).data;
+    await new Promise((r) => setTimeout(r, 100));
+  }
+  assert(output.includes("9"));
+
+  reader.close();
+  pty.close();
+});
+
+Deno.test({
+  name: "no output lost on fast exit",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    for (let i = 0; i < 100; i++) {
+      const pty = new Pty({
+        cmd: "sh",
+        args: ["-c", "printf 'hello world'; exit"],
+        env: [],
+      });
+      const { data } = await pty.collect(5000);
+      assertEquals(data, "hello world");
+      pty.close();
+    }
+  },
+});
+
+Deno.test("transcript", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+    record: true,
+  });
+
+  const { data } = await pty.collect(5000);
+  assert(data.includes("hello"));
+  // the output that was read is still in the transcript
+  assertEquals(new TextDecoder().decode(pty.transcript()), data);
+
+  pty.close();
+});
+
+Deno.test("asciinema recording", async () => {
+  const path = await Deno.makeTempFile({ suffix: ".cast" });
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+    size: { rows: 30, cols: 100, pixel_width: 0, pixel_height: 0 },
+    record_path: path,
+  });
+
+  await pty.collect(5000);
+  pty.close();
+
+  const lines = (await Deno.readTextFile(path)).trim().split("\n");
+  const [header, ...events] = lines.map((line) => JSON.parse(line));
+  assertEquals(header.version, 2);
+  assertEquals(header.width, 100);
+  assertEquals(header.height, 30);
+  assert(
+    events.every(([time, type]) => typeof time === "number" && type === "o"),
+  );
+  assert(events.map(([, , data]) => data).join("").includes("hello"));
+
+  await Deno.remove(path);
+});
+
+Deno.test("max buffered bytes", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "for (let i = 0; i < 1000; i++) console.log('line ' + i)"],
+    env: [["NO_COLOR", "1"]],
+    max_buffered_bytes: 1000,
+  });
+
+  // nothing is read while the process runs, so the old output is dropped
+  assert(await pty.wait(5000));
+  const { data } = await pty.collect(5000);
+  assert(/^\[\d+ bytes dropped\]\r\n/.test(data));
+  assert(data.includes("line 999"));
+  assert(!data.includes("line 0\r\n"));
+  assert(data.length < 2000);
+
+  pty.close();
+});
+
+Deno.test({
+  name: "env precedence",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    Deno.env.set("PTY_TEST_BASE", "base");
+    Deno.env.set("PTY_TEST_GONE", "base");
+    const pty = new Pty({
+      cmd: 'echo "$PTY_TEST_BASE-${PTY_TEST_GONE:-unset}"',
+      args: [],
+      env: { PTY_TEST_BASE: "override", PTY_TEST_GONE: "override" },
+      unset_env: ["PTY_TEST_GONE"],
+      shell: true,
+    });
+
+    const { data } = await pty.collect(5000);
+    assert(data.includes("override-unset"));
+
+    pty.close();
+    Deno.env.delete("PTY_TEST_BASE");
+    Deno.env.delete("PTY_TEST_GONE");
+  },
+});
+
+Deno.test({
+  name: "size env",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const colorterm = Deno.env.get("COLORTERM");
+    Deno.env.delete("COLORTERM");
+    const pty = new Pty({
+      cmd: 'echo "$COLUMNS $LINES $COLORTERM"',
+      args: [],
+      env: [],
+      size: { rows: 50, cols: 120, pixel_width: 0, pixel_height: 0 },
+      truecolor: true,
+      shell: true,
+    });
+
+    const { data } = await pty.collect(5000);
+    assert(data.includes("120 50 truecolor"));
+
+    pty.close();
+    if (colorterm !== undefined) Deno.env.set("COLORTERM", colorterm);
+  },
+});
+
+Deno.test({
+  name: "nice",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const pty = new Pty({
+      // it's set before the command runs
+      cmd: "nice",
+      args: [],
+      env: [],
+      nice: 5,
+    });
+
+    const { data } = await pty.collect(5000);
+    assert(data.includes("5"));
+
+    pty.close();
+  },
+});
+
+Deno.test("has ended", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+  });
+  const reader = pty.cloneReader();
+
+  await pty.wait();
+  // the reader thread sends End once it reaches EOF
+  while (!pty.hasEnded()) {
+    await new Promise((r) => setTimeout(r, 10));
+  }
+  // the clone wasn't finished, so it still gets the output
+  const { data } = await reader.read();
+  assert(data.includes("hello"));
+
+  reader.close();
+  pty.close();
+});
+
+Deno.test({
+  name: "custom path",
+  ignore: Deno.build.os === "windows",
+  fn: async () => {
+    const dir = await Deno.makeTempDir();
+    await Deno.writeTextFile(
+      `${dir}/hello-pty`,
+      "#!/bin/sh\necho hello $PATH\n",
+    );
+    await Deno.chmod(`${dir}/hello-pty`, 0o755);
+    const pty = new Pty({
+      cmd: "hello-pty",
+      args: [],
+      env: [],
+      path: `${dir}:/usr/bin:/bin`,
+    });
+
+    const { data } = await pty.collect(5000);
+    assert(data.includes(`hello ${dir}:/usr/bin:/bin`));
+
+    pty.close();
+    await Deno.remove(dir, { recursive: true });
+  },
+});
+
+Deno.test("peek", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assert(await pty.wait(5000));
+  // the end is sent after all the output is read
+  while (!pty.peek().done) {
+    await new Promise((r) => setTimeout(r, 10));
+  }
+  const peeked = pty.peek().data;
+  assert(peeked.includes("hello"));
+  const { data } = await pty.collect(5000);
+  assertEquals(data, peeked);
+
+  pty.close();
+});
+
+Deno.test("drain", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log('hello')"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  assert(await pty.wait(5000));
+  let output = "";
+  while (true) {
+    const { data, done } = pty.drain();
+    output += data;
+    if (done) break;
+    await new Promise((r) => setTimeout(r, 10));
+  }
+  assert(output.includes("hello"));
+  assertEquals(pty.drain(), { data: "", done: true });
+
+  pty.close();
+});
+
+Deno.test("exit callback", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "Deno.exit(3)"],
+  });
+  const exited = Promise.withResolvers<[number, number]>();
+  pty.onExit((pid, code) => exited.resolve([pid, code]));
+  const [pid, code] = await exited.promise;
+  assertEquals(pid, pty.pid());
+  assertEquals(code, 3);
+
+  // the process already exited, so the callback is called right away
+  const again = Promise.withResolvers<number>();
+  pty.onExit((_pid, code) => again.resolve(code));
+  assertEquals(await again.promise, 3);
+  pty.close();
+});
+
+Deno.test("pid", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log(Deno.pid)"],
+    env: [["NO_COLOR", "1"]],
+  });
+
+  const pid = pty.pid();
+  assert(pid !== undefined);
+
+  let output = "";
+  while (true) {
+    const r = await pty.readTimeout(5000);
+    assert(r !== undefined);
+    if (r.done) break;
+    output += r.data;
+  }
+  assert(output.includes(pid.toString()));
+
+  pty.close();
+});
+
+Deno.test("default env", async () => {
+  const term = Deno.env.get("TERM");
+  const command = {
+    cmd: Deno.execPath(),
+    args: ["eval", "console.log(Deno.env.get('TERM'))"],
+    env: [["NO_COLOR", "1"]] as [string, string][],
+  };
+
+  Deno.env.delete("TERM");
+  const pty = new Pty(command);
+  const { data } = await pty.collect(5000);
+  assert(data.includes("xterm-256color"));
+  pty.close();
+
+  // the inherited value is kept
+  Deno.env.set("TERM", "vt100");
+  const inherited = new Pty(command);
+  const { data: inheritedData } = await inherited.collect(5000);
+  assert(inheritedData.includes("vt100"));
+  inherited.close();
+
+  if (term === undefined) Deno.env.delete("TERM");
+  else Deno.env.set("TERM", term);
+});
+
+Deno.test("clear env", async () => {
+  const pty = new Pty({
+    cmd: Deno.execPath(),
+    args: ["eval", "console.log(JSON.stringify(Deno.env.toObject()))"],
+    env: [["NO_COLOR", "1"], ["A", "1"]],
+    clear_env: true,
+  });
+
+  let output = "";
+  while (true) {
+    const r = await pty.readTimeout(5000);
+    assert(r !== undefined);
+    if (r.done) break;
+    output += r.data;
+  }
+  assert(output.includes('"A":"1"'));
+  // windows may still set some system variables
+  if (Deno.build.os !== "windows") assert(!output.includes('"PATH"'));
+
+  pty.close();
+});
+
+Deno.test("inherit env", async () => {
+  Deno.env.set("PTY_INHERITED", "inherited");
+  const command = {
+    cmd: Deno.execPath(),
+    args: ["eval", "console.log(Deno.env.get('PTY_INHERITED') ?? 'unset')"],
+    env: [["NO_COLOR", "1"]] as [string, string][],
+  };
+
+  const pty = new Pty(command);
+  const { data } = await pty.collect(5000);
+  assert(data.includes("inherited"));
+  pty.close();
+
+  const cleared = new Pty({ ...command, clear_env: true });
+  const { data: clearedData } = await cleared.collect(5000);
+  assert(clearedData.includes("unset"));
+  assert(!clearedData.includes("inherited"));
+  cleared.close();
+
+  Deno.env.delete("PTY_INHERITED");
+});
+
+Deno.test("unset env", async () => {
+  const pty = new Pty({
+    cmd: "deno",
+    args: ["eval", "console.log(Deno.env.get('TERM') ?? 'unset')"],
+    env: [["NO_COLOR", "1"]],
+    unset_env: ["TERM"],
+  });
+
+  let output = "";
+  while (true) {
+    const r = await pty.readTimeout(5000);
+    assert(r !== undefined);
+    if (r.done) break;
+    output += r.data;
+  }
+  assert(output.includes("unset"));
+
+  pty.close();
+});
+
+Deno.test("invalid cwd", () => {
+  const error = assertThrows(
+    () =>
+      new Pty({
+        cmd: "deno",
+        args: ["repl"],
+        env: [],
+        cwd: "this-directory-does-not-exist",
+      }),
+    PtyError,
+    "cwd does not exist",
+  );
+  assertEquals(error.kind, ErrorKind.NotFound);
+});
+
+Deno.test("command not found", () => {
+  const error = assertThrows(
+    () =>
+      new Pty({
+        cmd: "this-command-does-not-exist",
+        args: [],
+        env: [],
+      }),
+    PtyError,
+    "command not found: this-command-does-not-exist",
+  );
+  assertEquals(error.kind, ErrorKind.NotFound);
+});
+
+Deno.test("invalid command", () => {
+  const empty = assertThrows(
+    () => new Pty({ cmd: "", args: [], env: [] }),
+    PtyError,
+    "empty command",
+  );
+  assertEquals(empty.kind, ErrorKind.InvalidInput);
+
+  const nullByte = assertThrows(
+    () => new Pty({ cmd: "echo", args: ["a\0b"], env: [] }),
+    PtyError,
+    "the command contains a null byte",
+  );
+  assertEquals(nullByte.kind, ErrorKind.InvalidString);
+});
+
+Deno.test("validate command", () => {
+  const cmd = Deno.build.os === "windows" ? "cmd.exe" : "sh";
+  validateCommand({ cmd, args: [], env: [] });
+
+  const missing = assertThrows(
+    () => validateCommand({ cmd: "does-not-exist", args: [], env: [] }),
+    PtyError,
+    "command not found: does-not-exist",
+  );
+  assertEquals(missing.kind, ErrorKind.NotFound);
+
+  const cwd = assertThrows(
+    () => validateCommand({ cmd, args: [], env: [], cwd: "does-not-exist" }),
+    PtyError,
+    "cwd does not exist",
+  );
+  assertEquals(cwd.kind, ErrorKind.NotFound);
+
+  const env = assertThrows(
+    () => validateCommand({ cmd, args: [], env: [["A=B", "c"]] }),
+    PtyError,
+    "invalid environment variable name",
+  );
+  assertEquals(env.kind, ErrorKind.InvalidInput);
+
+  // the PATH of the process is used for the lookup
+  assertThrows(
+    () => validateCommand({ cmd, args: [], env: [], path: "" }),
+    PtyError,
+    "command not found",
+  );
+});
+
 async function write_and_expect(pty: Pty, toWrite: string, expect: string) {
   await pty.write(toWrite);
 